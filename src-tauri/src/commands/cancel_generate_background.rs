@@ -0,0 +1,11 @@
+use tauri::State;
+
+use crate::commands::generate_background::GenerateBackgroundCancellation;
+
+/// 取消所有正在进行的背景水印图生成和水印自动定位
+#[tauri::command(async)]
+#[specta::specta]
+#[allow(clippy::needless_pass_by_value)]
+pub fn cancel_generate_background(cancellation: State<GenerateBackgroundCancellation>) {
+    cancellation.cancel();
+}
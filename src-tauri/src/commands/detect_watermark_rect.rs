@@ -0,0 +1,297 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use anyhow::{anyhow, Context};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use tauri::AppHandle;
+
+use crate::commands::generate_background::{create_image_paths, ScanProgress};
+use crate::errors::CommandResult;
+use crate::types::RectData;
+
+/// 最多用这么多张图片来定位水印，图片更多时均匀地抽取
+const MAX_DETECTION_PAGES: usize = 64;
+/// 至少需要这么多张图片，图片太少时漫画内容本身的边缘也会被当成水印
+const MIN_DETECTION_PAGES: usize = 4;
+/// 相邻像素的亮度差距不小于这个值时，认为这里有边缘
+const EDGE_THRESHOLD: u8 = 6;
+/// 一个像素在不少于这个比例的图片中都有边缘时，认为这里的边缘来自水印
+const MIN_EDGE_FREQUENCY: f32 = 0.8;
+/// 聚类时把图片划分为边长为这么多像素的格子
+const CELL_SIZE: u32 = 16;
+/// 格子中来自水印的边缘像素不少于这么多个时，才参与聚类，避免零散的噪点
+const MIN_CELL_EDGES: u32 = 4;
+/// 两个格子相隔不超过这么多格时属于同一块水印，让水印中相隔不远的文字连成一块
+const CELL_GAP: u32 = 2;
+/// 水印的宽或高超过图片的这个比例时，认为是页面边框之类的固定内容而不是水印
+const MAX_RECT_FRACTION: f32 = 0.5;
+/// 定位出的水印矩形向外扩展的像素数，保证截图区域的边上都是背景颜色
+const RECT_PADDING: u32 = 3;
+
+/// 分析`manga_dir`目录下所有尺寸为`width`x`height`的图片，自动定位水印所在的矩形
+///
+/// 与生成背景水印图一样，可以通过`cancel_generate_background`取消
+#[tauri::command(async)]
+#[specta::specta]
+#[allow(clippy::needless_pass_by_value)]
+pub fn detect_watermark_rect(
+    app: AppHandle,
+    manga_dir: &str,
+    width: u32,
+    height: u32,
+) -> CommandResult<RectData> {
+    let image_paths = create_image_paths(manga_dir, width, height);
+    let progress = ScanProgress::new(&app, width, height);
+    let rect_data = detect_rect(&image_paths, width, height, Some(&progress))?;
+    Ok(rect_data)
+}
+
+/// 分析`image_paths`中尺寸为`width`x`height`的图片，返回紧贴水印的矩形，`progress`不为`None`时在被取消时返回错误
+///
+/// 漫画内容在每张图片中的位置都不同，而水印总是覆盖在同一个位置  
+/// 所以统计每个像素在多少张图片中处于边缘，大部分图片中都处于边缘的像素就是水印的轮廓  
+/// 再把这些像素按照距离聚成若干块，排除过大的块后取像素最多的那块，它的外接矩形就是水印所在的矩形
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+pub fn detect_rect(
+    image_paths: &[PathBuf],
+    width: u32,
+    height: u32,
+    progress: Option<&ScanProgress>,
+) -> anyhow::Result<RectData> {
+    if image_paths.len() < MIN_DETECTION_PAGES {
+        return Err(anyhow!(
+            "尺寸为({width}x{height})的图片只有{}张，至少需要{MIN_DETECTION_PAGES}张才能自动定位水印",
+            image_paths.len()
+        ));
+    }
+    // 均匀地抽取图片，避免只用到开头的几个章节
+    let step = image_paths.len().div_ceil(MAX_DETECTION_PAGES);
+    let sampled_paths: Vec<&PathBuf> = image_paths.iter().step_by(step).collect();
+    let edge_counts = count_edges(&sampled_paths, width, height, progress)?;
+    let min_count = (sampled_paths.len() as f32 * MIN_EDGE_FREQUENCY).ceil() as u16;
+    let is_watermark_edge: Vec<bool> = edge_counts.iter().map(|&c| c >= min_count).collect();
+
+    // 统计每个格子中来自水印的边缘像素数量
+    let cells_x = width.div_ceil(CELL_SIZE);
+    let cells_y = height.div_ceil(CELL_SIZE);
+    let mut cell_counts = vec![0_u32; (cells_x * cells_y) as usize];
+    for (index, _) in is_watermark_edge.iter().enumerate().filter(|(_, &e)| e) {
+        let (x, y) = (index as u32 % width, index as u32 / width);
+        cell_counts[(y / CELL_SIZE * cells_x + x / CELL_SIZE) as usize] += 1;
+    }
+
+    let max_width = width as f32 * MAX_RECT_FRACTION;
+    let max_height = height as f32 * MAX_RECT_FRACTION;
+    let rect_data = cell_clusters(&cell_counts, cells_x, cells_y)
+        .into_iter()
+        .filter_map(|cluster| {
+            let edge_count: u32 = cluster.iter().map(|&cell| cell_counts[cell]).sum();
+            let rect_data = cluster_rect(&cluster, &is_watermark_edge, cells_x, width, height)?;
+            let rect_width = (rect_data.right - rect_data.left + 1) as f32;
+            let rect_height = (rect_data.bottom - rect_data.top + 1) as f32;
+            (rect_width <= max_width && rect_height <= max_height)
+                .then_some((edge_count, rect_data))
+        })
+        .max_by_key(|(edge_count, _)| *edge_count)
+        .map(|(_, rect_data)| rect_data)
+        .ok_or(anyhow!(
+            "在尺寸为({width}x{height})的图片中找不到位置固定的水印，请手动截取水印"
+        ))?;
+
+    let rect_data = RectData {
+        left: rect_data.left.saturating_sub(RECT_PADDING),
+        top: rect_data.top.saturating_sub(RECT_PADDING),
+        right: (rect_data.right + RECT_PADDING).min(width - 1),
+        bottom: (rect_data.bottom + RECT_PADDING).min(height - 1),
+    };
+    Ok(rect_data)
+}
+
+/// 统计每个像素在`image_paths`的多少张图片中处于边缘，尺寸不是`width`x`height`的图片跳过
+///
+/// 所有线程累加到同一个计数缓冲区，不为每个线程分配整张图片大小的缓冲区
+fn count_edges(
+    image_paths: &[&PathBuf],
+    width: u32,
+    height: u32,
+    progress: Option<&ScanProgress>,
+) -> anyhow::Result<Vec<u16>> {
+    let pixel_count = (width * height) as usize;
+    let counts: Vec<AtomicU16> = (0..pixel_count).map(|_| AtomicU16::new(0)).collect();
+    image_paths
+        .par_iter()
+        .try_for_each(|path| -> anyhow::Result<()> {
+            if let Some(progress) = progress {
+                progress.check_cancelled()?;
+            }
+            let img = image::open(path)
+                .context(format!("打开图片 {path:?} 失败"))?
+                .to_luma8();
+            if img.dimensions() != (width, height) {
+                return Ok(());
+            }
+            let is_edge = |a: &image::Luma<u8>, b: &image::Luma<u8>| {
+                a.0[0].abs_diff(b.0[0]) >= EDGE_THRESHOLD
+            };
+            for (x, y, pixel) in img.enumerate_pixels() {
+                let right_edge = x + 1 < width && is_edge(pixel, img.get_pixel(x + 1, y));
+                let bottom_edge = y + 1 < height && is_edge(pixel, img.get_pixel(x, y + 1));
+                if right_edge || bottom_edge {
+                    counts[(y * width + x) as usize].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            Ok(())
+        })?;
+    Ok(counts.into_iter().map(AtomicU16::into_inner).collect())
+}
+
+/// 把边缘像素不少于`MIN_CELL_EDGES`的格子聚成若干块，相隔不超过`CELL_GAP`格的格子属于同一块
+#[allow(clippy::cast_possible_truncation)]
+fn cell_clusters(cell_counts: &[u32], cells_x: u32, cells_y: u32) -> Vec<Vec<usize>> {
+    let is_active = |cell: usize| cell_counts[cell] >= MIN_CELL_EDGES;
+    let mut visited = vec![false; cell_counts.len()];
+    let mut clusters = vec![];
+    for start in 0..cell_counts.len() {
+        if visited[start] || !is_active(start) {
+            continue;
+        }
+        visited[start] = true;
+        let mut cluster = vec![];
+        let mut queue = VecDeque::from([start]);
+        while let Some(cell) = queue.pop_front() {
+            cluster.push(cell);
+            let (cx, cy) = (cell as u32 % cells_x, cell as u32 / cells_x);
+            let xs = cx.saturating_sub(CELL_GAP)..=(cx + CELL_GAP).min(cells_x - 1);
+            let ys = cy.saturating_sub(CELL_GAP)..=(cy + CELL_GAP).min(cells_y - 1);
+            for ny in ys {
+                for nx in xs.clone() {
+                    let neighbour = (ny * cells_x + nx) as usize;
+                    if !visited[neighbour] && is_active(neighbour) {
+                        visited[neighbour] = true;
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+        }
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+/// 块`cluster`中所有来自水印的边缘像素的外接矩形
+#[allow(clippy::cast_possible_truncation)]
+fn cluster_rect(
+    cluster: &[usize],
+    is_watermark_edge: &[bool],
+    cells_x: u32,
+    width: u32,
+    height: u32,
+) -> Option<RectData> {
+    let mut rect_data: Option<RectData> = None;
+    for &cell in cluster {
+        let (cx, cy) = (cell as u32 % cells_x, cell as u32 / cells_x);
+        let xs = cx * CELL_SIZE..((cx + 1) * CELL_SIZE).min(width);
+        let ys = cy * CELL_SIZE..((cy + 1) * CELL_SIZE).min(height);
+        for y in ys {
+            for x in xs.clone() {
+                if !is_watermark_edge[(y * width + x) as usize] {
+                    continue;
+                }
+                rect_data = Some(match rect_data {
+                    Some(r) => RectData {
+                        left: r.left.min(x),
+                        top: r.top.min(y),
+                        right: r.right.max(x),
+                        bottom: r.bottom.max(y),
+                    },
+                    None => RectData {
+                        left: x,
+                        top: y,
+                        right: x,
+                        bottom: y,
+                    },
+                });
+            }
+        }
+    }
+    rect_data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use image::{Luma, Rgb, RgbImage};
+
+    use super::*;
+
+    /// 在`dir`中保存`count`张120x160的图片，每张图片的内容都不同，`watermark`为true时右下角有位置固定的水印
+    ///
+    /// 图片上方还有一条贯穿整个宽度的固定边框，它的宽度超过了`MAX_RECT_FRACTION`，不应该被当成水印
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn save_pages(dir: &Path, count: u32, watermark: bool) -> anyhow::Result<Vec<PathBuf>> {
+        std::fs::create_dir_all(dir)?;
+        // 水印覆盖(80, 130)到(103, 141)，alpha每隔两列在0.5和0.8之间交替
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (80..=103, 130..=141) if x % 4 < 2 => 0.5,
+            (80..=103, 130..=141) => 0.8,
+            _ => 1.0,
+        };
+        let mut paths = vec![];
+        for page in 0..count {
+            // 每张图片的渐变方向和内容方块的位置都不同
+            let (block_x, block_y) = ((page * 37) % 90, (page * 53) % 120);
+            let img = RgbImage::from_fn(120, 160, |x, y| {
+                let base = if (block_x..block_x + 24).contains(&x)
+                    && (block_y..block_y + 30).contains(&y)
+                {
+                    30
+                } else {
+                    (60 + (x * page + y * (count - page)) / 8 % 80) as u8
+                };
+                let value = if y == 20 { 0 } else { base };
+                let alpha = if watermark { alpha(x, y) } else { 1.0 };
+                let value = f32::from(value) * alpha + 255.0 * (1.0 - alpha);
+                Rgb([value.round() as u8; 3])
+            });
+            let path = dir.join(format!("{page:02}.png"));
+            img.save(&path)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    #[test]
+    fn finds_watermark_fixed_across_pages() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("detect-rect-{}", std::process::id()));
+        let mut paths = save_pages(&dir, 8, true)?;
+        // 尺寸不同的图片被跳过
+        let other_size = dir.join("other.png");
+        image::GrayImage::from_pixel(60, 80, Luma([128])).save(&other_size)?;
+        paths.push(other_size);
+
+        let rect = detect_rect(&paths, 120, 160, None)?;
+        // 水印边缘的两侧都算作边缘，再向外扩展RECT_PADDING
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (76, 126, 106, 144)
+        );
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_too_few_pages_or_no_fixed_watermark() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("detect-no-rect-{}", std::process::id()));
+        let watermarked = save_pages(&dir.join("watermarked"), 3, true)?;
+        assert!(detect_rect(&watermarked, 120, 160, None).is_err());
+        let clean = save_pages(&dir.join("clean"), 8, false)?;
+        assert!(detect_rect(&clean, 120, 160, None).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -1,16 +1,124 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context};
-use image::RgbImage;
-use parking_lot::Mutex;
+use image::{Rgb, RgbImage};
+use parking_lot::{Mutex, RwLock};
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use tauri::AppHandle;
+use tauri::{AppHandle, Manager, State};
+use tauri_specta::Event;
 use walkdir::WalkDir;
 
+use crate::commands::detect_watermark_rect::detect_rect;
+use crate::config::Config;
 use crate::errors::CommandResult;
-use crate::types::RectData;
+use crate::events;
+use crate::types::{BackgroundCandidate, BackgroundThresholds, RectData};
 use crate::utils;
+use crate::watermark::MIN_LEVEL_DIFFERENCE;
 
+/// 同一种背景颜色的图片，截图区域内与某种水印的平均值的平均差距不超过这个值时，认为是同一种水印
+const CLUSTER_TOLERANCE: f32 = 5.0;
+/// 不同背景颜色的背景水印图，水印特征的相关系数不小于这个值时，认为是同一种水印
+const MIN_WATERMARK_CORRELATION: f32 = 0.6;
+/// 两次发送`GenerateBackgroundProgressEvent`事件的最短间隔，避免扫描大量图片时前端收到过多事件
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 用于取消正在进行的背景水印图生成
+///
+/// 每次取消都让纪元加1，生成开始时记下当时的纪元，纪元变化后就视为被取消  
+/// 所以取消只影响已经开始的生成，不影响之后开始的生成
+#[derive(Default)]
+pub struct GenerateBackgroundCancellation {
+    epoch: AtomicU64,
+}
+
+impl GenerateBackgroundCancellation {
+    /// 取消所有正在进行的背景水印图生成
+    pub fn cancel(&self) {
+        self.epoch.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::SeqCst)
+    }
+}
+
+/// 扫描图片的进度，用于发送`GenerateBackgroundProgressEvent`事件和响应取消
+pub struct ScanProgress<'a> {
+    app: &'a AppHandle,
+    width: u32,
+    height: u32,
+    /// 开始生成时的取消纪元
+    epoch: u64,
+    /// 已扫描的图片数量
+    current: AtomicU32,
+    /// 已找到的满足背景条件的图片数量
+    candidates: AtomicU32,
+    /// 上次发送进度事件的时间
+    last_emitted: Mutex<Instant>,
+}
+
+impl<'a> ScanProgress<'a> {
+    pub fn new(app: &'a AppHandle, width: u32, height: u32) -> Self {
+        Self {
+            app,
+            width,
+            height,
+            epoch: app.state::<GenerateBackgroundCancellation>().epoch(),
+            current: AtomicU32::new(0),
+            candidates: AtomicU32::new(0),
+            last_emitted: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// 生成或自动定位水印已被取消时返回错误
+    pub fn check_cancelled(&self) -> anyhow::Result<()> {
+        let cancellation = self.app.state::<GenerateBackgroundCancellation>();
+        if cancellation.epoch() != self.epoch {
+            let (width, height) = (self.width, self.height);
+            return Err(anyhow!("扫描尺寸为({width}x{height})的图片已取消"));
+        }
+        Ok(())
+    }
+
+    /// 记录扫描完一张图片，`is_candidate`表示这张图片是否满足背景条件
+    ///
+    /// 距离上次发送进度事件不到`PROGRESS_EVENT_INTERVAL`时不发送，其他线程正在发送时也不发送
+    fn advance(&self, is_candidate: bool) -> anyhow::Result<()> {
+        let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+        let candidates = if is_candidate {
+            self.candidates.fetch_add(1, Ordering::SeqCst) + 1
+        } else {
+            self.candidates.load(Ordering::SeqCst)
+        };
+        {
+            let Some(mut last_emitted) = self.last_emitted.try_lock() else {
+                return Ok(());
+            };
+            if last_emitted.elapsed() < PROGRESS_EVENT_INTERVAL {
+                return Ok(());
+            }
+            *last_emitted = Instant::now();
+        }
+        // 发送GenerateBackgroundProgressEvent事件
+        let payload = events::GenerateBackgroundProgressEventPayload {
+            width: self.width,
+            height: self.height,
+            current,
+            candidates,
+        };
+        let event = events::GenerateBackgroundProgressEvent(payload);
+        event.emit(self.app)?;
+        Ok(())
+    }
+}
+
+/// 生成`manga_dir`目录下尺寸为`width`x`height`的背景水印图
+///
+/// 没有截取水印时自动定位水印，定位失败时使用默认的截图区域，并返回说明原因的警告
 #[tauri::command(async)]
 #[specta::specta]
 #[allow(clippy::cast_possible_truncation)]
@@ -19,111 +127,328 @@ use crate::utils;
 #[allow(clippy::cast_precision_loss)]
 pub fn generate_background(
     app: AppHandle,
+    config: State<RwLock<Config>>,
     manga_dir: &str,
     rect_data: Option<RectData>,
     width: u32,
     height: u32,
-) -> CommandResult<()> {
+) -> CommandResult<Option<String>> {
+    let thresholds = config.read().background_thresholds;
+    let progress = ScanProgress::new(&app, width, height);
     let output_dir = utils::get_background_dir_abs_path(&app, manga_dir, width, height)?;
-    // TODO: 给RectData实现Default trait，以替换下面的代码
-    let default_rect_data = RectData {
-        left: (width as f32 * 0.835) as u32,
-        top: (height as f32 * 0.946) as u32,
-        right: (width as f32 * 0.994) as u32,
-        bottom: (height as f32 * 0.994) as u32,
-    };
-    let rect_data = rect_data.unwrap_or(default_rect_data);
 
     // 保证输出目录存在
     std::fs::create_dir_all(&output_dir).context(format!("创建目录 {output_dir:?} 失败"))?;
     // 收集尺寸符合width和height的图片的路径
     let image_paths = create_image_paths(manga_dir, width, height);
-    // 用于保存各种符合条件的背景水印图
-    let backgrounds = Mutex::new(vec![]);
-    // 用于标记是否找到了黑色和白色背景水印图
-    let background_pair_found = Mutex::new(false);
-    // 并发遍历image_paths
-    let image_paths = image_paths.par_iter();
-    image_paths.try_for_each(|path| -> anyhow::Result<()> {
-        // 如果已经找到了黑色和白色背景水印图，则直接返回
-        if *background_pair_found.lock() {
-            return Ok(());
-        }
-
-        let mut img = image::open(path)
-            .context(format!("打开图片 {path:?} 失败"))?
-            .to_rgb8();
-        // 如果图片不满足背景的条件，则直接跳过
-        if !is_background(&img, &rect_data) {
-            return Ok(());
-        };
-        // 获取左上角的颜色
-        let (left, top) = (rect_data.left, rect_data.top);
-        let (right, bottom) = (rect_data.right, rect_data.bottom);
-        let color = *img.get_pixel(left, top);
-        // 把截图区域外的像素点设置为左上角的颜色
-        for (x, y, pixel) in img.enumerate_pixels_mut() {
-            if x < left || x > right || y < top || y > bottom {
-                *pixel = color;
-            }
-        }
-        let mut backgrounds = backgrounds.lock();
-        backgrounds.push(img);
-        // 按照像素值排序，保证黑色背景水印图在前，白色背景水印图在后
-        backgrounds.sort_by(|a, b| {
-            let a_color = a.get_pixel(0, 0);
-            let b_color = b.get_pixel(0, 0);
-            a_color[0].cmp(&b_color[0])
-        });
-        if backgrounds.len() < 2 {
-            return Ok(());
-        }
-
-        let black = &backgrounds[0];
-        let white = &backgrounds[backgrounds.len() - 1];
-        // 如果黑色和白色背景水印图的像素值差异大于50，则认为找到了黑色和白色背景水印图
-        let black_color = black.get_pixel(0, 0);
-        let white_color = white.get_pixel(0, 0);
-        if white_color[0] - black_color[0] > 50 {
-            *background_pair_found.lock() = true;
-        }
-
-        Ok(())
-    })?;
+    // 发送GenerateBackgroundStartEvent事件
+    let payload = events::GenerateBackgroundStartEventPayload {
+        width,
+        height,
+        total: image_paths.len() as u32,
+    };
+    let event = events::GenerateBackgroundStartEvent(payload);
+    event.emit(&app).map_err(anyhow::Error::from)?;
+    // 定位水印后，每种背景颜色的每种水印各生成一张背景水印图
+    let scan_result = rect_or_detect(rect_data, &image_paths, width, height, &progress).and_then(
+        |(rect_data, warning)| {
+            let (backgrounds, rejections) = create_backgrounds(
+                &image_paths,
+                width,
+                height,
+                &rect_data,
+                thresholds,
+                Some(&progress),
+            )?;
+            Ok((rect_data, warning, backgrounds, rejections))
+        },
+    );
+    // 定位和扫描结束后(包括失败和取消)发送GenerateBackgroundEndEvent事件
+    let payload = events::GenerateBackgroundEndEventPayload { width, height };
+    let event = events::GenerateBackgroundEndEvent(payload);
+    event.emit(&app).map_err(anyhow::Error::from)?;
+    let (rect_data, warning, backgrounds, rejections) = scan_result?;
+    // 把背景水印图按照水印分组，每组找出一对黑色和白色背景水印图
+    let groups = group_by_watermark(&backgrounds, &rect_data);
+    let background_pairs: Vec<(&Background, &Background)> = groups
+        .iter()
+        .filter_map(|group| find_background_pair(group))
+        .collect();
 
-    let backgrounds = std::mem::take(&mut *backgrounds.lock());
-    let background_pair_found = std::mem::take(&mut *background_pair_found.lock());
-    // 如果有第一张背景水印图，则将其保存为黑色背景
-    if let Some(black) = backgrounds.first() {
-        let black_output_path = output_dir.join("black.png");
+    remove_background_variants(&output_dir)?;
+    for (index, (black, white)) in background_pairs.iter().enumerate() {
+        // 找到了黑色和白色背景水印图，按照亮度分别保存为黑色背景和白色背景
+        let (black_filename, white_filename) = utils::get_background_filenames(index);
+        let black_output_path = output_dir.join(black_filename);
+        let white_output_path = output_dir.join(white_filename);
         black
+            .img
             .save(&black_output_path)
             .context(format!("保存图片 {black_output_path:?} 失败",))?;
-    }
-    // 如果找到了黑色和白色背景水印图
-    if background_pair_found {
-        // 把最后一张背景水印图保存为白色背景
-        let white = &backgrounds[backgrounds.len() - 1];
-        let white_output_path = output_dir.join("white.png");
         white
+            .img
             .save(&white_output_path)
             .context(format!("保存图片 {white_output_path:?} 失败",))?;
     }
+    if background_pairs.is_empty() {
+        // 只找到了一种背景颜色，保存图片数量最多的那张，根据亮度决定保存为黑色背景还是白色背景
+        if let Some(background) = backgrounds.iter().max_by_key(|background| background.count) {
+            let (black_filename, white_filename) = utils::get_background_filenames(0);
+            let output_path = if luma(background.color) < 128.0 {
+                output_dir.join(black_filename)
+            } else {
+                output_dir.join(white_filename)
+            };
+            background
+                .img
+                .save(&output_path)
+                .context(format!("保存图片 {output_path:?} 失败",))?;
+        }
+    }
 
+    let mut summary = rejection_summary(&rejections);
+    if let Some(warning) = &warning {
+        summary = format!("{warning}\n{summary}");
+    }
     if backgrounds.is_empty() {
-        return Err(anyhow!("找不到尺寸为({width}x{height})的背景水印图\n").into());
-    } else if !background_pair_found {
-        return Err(anyhow!("只找到一张尺寸为({width}x{height})的背景水印图\n").into());
+        return Err(anyhow!("找不到尺寸为({width}x{height})的背景水印图\n{summary}").into());
+    } else if background_pairs.is_empty() {
+        return Err(
+            anyhow!("只找到一种背景颜色的尺寸为({width}x{height})的背景水印图\n{summary}").into(),
+        );
     };
 
+    Ok(warning)
+}
+
+/// 有截取的水印`rect_data`时直接使用，否则根据`image_paths`自动定位水印
+///
+/// 定位失败时使用默认的截图区域(图片右下角)，同时返回说明原因的警告，被取消时返回错误
+fn rect_or_detect(
+    rect_data: Option<RectData>,
+    image_paths: &[PathBuf],
+    width: u32,
+    height: u32,
+    progress: &ScanProgress,
+) -> anyhow::Result<(RectData, Option<String>)> {
+    if let Some(rect_data) = rect_data {
+        return Ok((rect_data, None));
+    }
+    match detect_rect(image_paths, width, height, Some(progress)) {
+        Ok(rect_data) => Ok((rect_data, None)),
+        Err(err) => {
+            // 被取消不是定位失败，不使用默认的截图区域
+            progress.check_cancelled()?;
+            let warning = format!("自动定位水印失败，已使用默认的截图区域: {err:#}");
+            Ok((default_rect_data(width, height), Some(warning)))
+        }
+    }
+}
+
+/// 默认的截图区域，大部分漫画的水印都在右下角的这个位置
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+#[allow(clippy::cast_precision_loss)]
+fn default_rect_data(width: u32, height: u32) -> RectData {
+    RectData {
+        left: (width as f32 * 0.835) as u32,
+        top: (height as f32 * 0.946) as u32,
+        right: (width as f32 * 0.994) as u32,
+        bottom: (height as f32 * 0.994) as u32,
+    }
+}
+
+/// 从`image_paths`中收集所有满足背景条件的图片，每种背景颜色的每种水印各取平均，生成一张背景水印图
+///
+/// 同时返回所有不满足背景条件的图片及其原因，`progress`不为`None`时报告扫描进度，并在被取消时返回错误
+fn create_backgrounds(
+    image_paths: &[PathBuf],
+    width: u32,
+    height: u32,
+    rect_data: &RectData,
+    thresholds: BackgroundThresholds,
+    progress: Option<&ScanProgress>,
+) -> anyhow::Result<(Vec<Background>, Vec<RejectedImage>)> {
+    // 按照路径排序，保证每次都以相同的顺序聚类，生成的背景水印图只取决于输入的图片
+    let mut image_paths: Vec<&PathBuf> = image_paths.iter().collect();
+    image_paths.sort();
+    // 并发检查每张图片是否满足背景的条件，满足时取出背景颜色和截图区域内的像素，结果的顺序与image_paths一致
+    let checked_images = image_paths
+        .par_iter()
+        .map(|path| -> anyhow::Result<_> {
+            if let Some(progress) = progress {
+                progress.check_cancelled()?;
+            }
+            let img = image::open(path)
+                .context(format!("打开图片 {path:?} 失败"))?
+                .to_rgb8();
+            let checked = check_background(&img, rect_data, thresholds)
+                .map(|stats| (stats.color, rect_patch(&img, rect_data)));
+            if let Some(progress) = progress {
+                progress.advance(checked.is_ok())?;
+            }
+            Ok(checked)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    // 用于累加各种背景颜色的背景水印图，color => [accumulator1, accumulator2, ...]
+    // 同一种背景颜色下，每种水印各有一个累加器
+    let mut accumulators: BTreeMap<[u8; 3], Vec<BackgroundAccumulator>> = BTreeMap::new();
+    let mut rejections: Vec<RejectedImage> = vec![];
+    // 聚类的结果与图片的顺序有关，所以按照image_paths的顺序依次累加，不能并发
+    for (path, checked) in image_paths.into_iter().zip(checked_images) {
+        // 如果图片不满足背景的条件，则记录原因后跳过
+        let (color, patch) = match checked {
+            Ok(checked) => checked,
+            Err(rejection) => {
+                rejections.push((path.clone(), rejection));
+                continue;
+            }
+        };
+        // 相同背景颜色且相同水印的图片累加到一起，与已有的背景颜色足够接近时视为同一种背景颜色
+        let color = accumulators
+            .keys()
+            .copied()
+            .find(|&key| is_similar_color(key, color, thresholds.color_tolerance))
+            .unwrap_or(color);
+        let variants = accumulators.entry(color).or_default();
+        let same_watermark = variants
+            .iter_mut()
+            .find(|accumulator| accumulator.mean_difference(&patch) <= CLUSTER_TOLERANCE);
+        match same_watermark {
+            Some(accumulator) => accumulator.add(&patch),
+            None => {
+                let mut accumulator = BackgroundAccumulator::new(rect_data);
+                accumulator.add(&patch);
+                variants.push(accumulator);
+            }
+        }
+    }
+    // 每种背景颜色的每种水印的所有图片取平均，得到噪点更少的背景水印图
+    let backgrounds = accumulators
+        .into_iter()
+        .flat_map(|(color, variants)| variants.into_iter().map(move |acc| (color, acc)))
+        .map(|(color, accumulator)| Background {
+            color,
+            count: accumulator.count,
+            img: accumulator.to_background(color, width, height, rect_data),
+        })
+        .collect();
+    Ok((backgrounds, rejections))
+}
+
+/// 从`image_paths`中生成每种水印的一对(黑色背景, 白色背景)水印图，找不到任何一对时返回空的`Vec`
+pub fn generate_background_pairs(
+    image_paths: &[PathBuf],
+    width: u32,
+    height: u32,
+    rect_data: &RectData,
+    thresholds: BackgroundThresholds,
+) -> anyhow::Result<Vec<(RgbImage, RgbImage)>> {
+    let (backgrounds, _) =
+        create_backgrounds(image_paths, width, height, rect_data, thresholds, None)?;
+    let background_pairs = group_by_watermark(&backgrounds, rect_data)
+        .iter()
+        .filter_map(|group| find_background_pair(group))
+        .map(|(black, white)| (black.img.clone(), white.img.clone()))
+        .collect();
+    Ok(background_pairs)
+}
+
+/// 列出`image_paths`中所有满足背景条件的图片，按照噪点从少到多排列，噪点相同时水印覆盖的比例大的在前
+pub fn background_candidates(
+    image_paths: &[PathBuf],
+    rect_data: &RectData,
+    thresholds: BackgroundThresholds,
+) -> anyhow::Result<Vec<BackgroundCandidate>> {
+    let mut candidates: Vec<BackgroundCandidate> = image_paths
+        .par_iter()
+        .map(|path| -> anyhow::Result<Option<BackgroundCandidate>> {
+            let img = image::open(path)
+                .context(format!("打开图片 {path:?} 失败"))?
+                .to_rgb8();
+            let candidate = check_background(&img, rect_data, thresholds)
+                .ok()
+                .map(|stats| BackgroundCandidate {
+                    path: path.clone(),
+                    color: stats.color,
+                    noise: stats.noise,
+                    coverage: stats.coverage,
+                });
+            Ok(candidate)
+        })
+        .filter_map(Result::transpose)
+        .collect::<anyhow::Result<_>>()?;
+    candidates.sort_by(|a, b| {
+        a.noise
+            .total_cmp(&b.noise)
+            .then(b.coverage.total_cmp(&a.coverage))
+            .then(a.path.cmp(&b.path))
+    });
+    Ok(candidates)
+}
+
+/// 用用户选出的一对图片`black_path`和`white_path`生成背景水印图，保存为第一种水印的背景水印图到`output_dir`
+///
+/// 两张图片都必须满足背景的条件，并且每个通道的背景颜色差距都不小于`MIN_LEVEL_DIFFERENCE`  
+/// 之前生成的所有背景水印图都会被删除
+pub fn save_background_pair(
+    output_dir: &Path,
+    black_path: &Path,
+    white_path: &Path,
+    width: u32,
+    height: u32,
+    rect_data: &RectData,
+    thresholds: BackgroundThresholds,
+) -> anyhow::Result<()> {
+    let mut pair = vec![];
+    for path in [black_path, white_path] {
+        let img = image::open(path)
+            .context(format!("打开图片 {path:?} 失败"))?
+            .to_rgb8();
+        if img.dimensions() != (width, height) {
+            let (img_width, img_height) = img.dimensions();
+            return Err(anyhow!(
+                "图片 {path:?} 的尺寸({img_width}x{img_height})与({width}x{height})不同"
+            ));
+        }
+        let stats = check_background(&img, rect_data, thresholds)
+            .map_err(|rejection| anyhow!("图片 {path:?} 不能用作背景水印图，{rejection}"))?;
+        let mut accumulator = BackgroundAccumulator::new(rect_data);
+        accumulator.add(&rect_patch(&img, rect_data));
+        pair.push(Background {
+            color: stats.color,
+            count: 1,
+            img: accumulator.to_background(stats.color, width, height, rect_data),
+        });
+    }
+    let refs: Vec<&Background> = pair.iter().collect();
+    let (black, white) = find_background_pair(&refs).ok_or(anyhow!(
+        "图片 {black_path:?} 和 {white_path:?} 的背景颜色太接近，每个通道至少要相差{MIN_LEVEL_DIFFERENCE}"
+    ))?;
+
+    std::fs::create_dir_all(output_dir).context(format!("创建目录 {output_dir:?} 失败"))?;
+    remove_background_variants(output_dir)?;
+    let (black_filename, white_filename) = utils::get_background_filenames(0);
+    let black_output_path = output_dir.join(black_filename);
+    let white_output_path = output_dir.join(white_filename);
+    black
+        .img
+        .save(&black_output_path)
+        .context(format!("保存图片 {black_output_path:?} 失败",))?;
+    white
+        .img
+        .save(&white_output_path)
+        .context(format!("保存图片 {white_output_path:?} 失败",))?;
     Ok(())
 }
 
-/// 遍历`manga_dir`目录下的所有jpg文件，收集尺寸符合`width`和`height`的图片的路径
+/// 遍历`manga_dir`目录下的所有图片，收集尺寸符合`width`和`height`的图片的路径
 #[allow(clippy::cast_possible_truncation)]
-fn create_image_paths(manga_dir: &str, width: u32, height: u32) -> Vec<PathBuf> {
+pub fn create_image_paths(manga_dir: &str, width: u32, height: u32) -> Vec<PathBuf> {
     let image_paths: Vec<PathBuf> = WalkDir::new(PathBuf::from(manga_dir))
         .max_depth(2) // 一般第一层目录是章节目录，第二层目录是图片文件
+        .sort_by_file_name() // 保证每次收集到的图片顺序相同
         .into_iter()
         .filter_map(Result::ok)
         .filter_map(|entry| {
@@ -131,8 +456,7 @@ fn create_image_paths(manga_dir: &str, width: u32, height: u32) -> Vec<PathBuf>
             if !path.is_file() {
                 return None;
             }
-            let ext = path.extension()?.to_str()?.to_lowercase();
-            if ext != "jpg" && ext != "jpeg" {
+            if !utils::is_supported_image(&path) {
                 return None;
             }
             // 只收集尺寸符合width和height的图片的路径
@@ -147,39 +471,502 @@ fn create_image_paths(manga_dir: &str, width: u32, height: u32) -> Vec<PathBuf>
     image_paths
 }
 
-/// 检查图片`img`是否满足背景的条件
+/// 图片不满足背景条件的原因
+enum Rejection {
+    /// 截图区域的边上不是纯色，`solid_ratio`为边上是背景颜色的像素比例
+    BorderNotSolid { solid_ratio: f32 },
+    /// 截图区域内几乎全是背景颜色，没有水印，`plain_ratio`为截图区域内是背景颜色的像素比例
+    NoWatermark { plain_ratio: f32 },
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::BorderNotSolid { solid_ratio } => write!(
+                f,
+                "截图区域的边上不是纯色，只有{:.1}%的像素是背景颜色",
+                solid_ratio * 100.0
+            ),
+            Rejection::NoWatermark { plain_ratio } => write!(
+                f,
+                "截图区域内没有水印，有{:.1}%的像素是背景颜色",
+                plain_ratio * 100.0
+            ),
+        }
+    }
+}
+
+/// 满足背景条件的图片的统计数据
+struct BackgroundStats {
+    /// 截图区域的边上的纯色背景颜色
+    color: [u8; 3],
+    /// 截图区域的边上的像素每个通道与背景颜色的平均差距
+    noise: f32,
+    /// 截图区域内不是背景颜色的像素比例
+    coverage: f32,
+}
+
+/// 不满足背景条件的图片及其原因，(图片路径, 原因)
+type RejectedImage = (PathBuf, Rejection);
+
+/// 检查图片`img`是否满足背景的条件，满足时返回背景颜色等统计数据，不满足时返回原因
+///
+/// 背景颜色不要求是灰色，只要是纯色即可，取截图区域的边上所有像素每个通道的中位数  
+/// JPEG的噪点会让纯色背景上的像素略有差异，所以与背景颜色的差距不超过`thresholds.color_tolerance`的像素都视为背景颜色
 #[allow(clippy::cast_precision_loss)]
-fn is_background(img: &RgbImage, rect_data: &RectData) -> bool {
-    let (left, top) = (rect_data.left, rect_data.top);
-    let (right, bottom) = (rect_data.right, rect_data.bottom);
-    let inside_rect = |x: u32, y: u32| x >= left && x <= right && y >= top && y <= bottom;
-    // 获取左上角的颜色
-    let color = *img.get_pixel(left, top);
-    let [r, g, b] = color.0;
-    // 如果r,g,b通道之间不相等，则不满足背景的条件
-    if r != g || g != b {
-        return false;
-    }
-    // 如果截图区域的左右两边的颜色有一个与左上角的颜色不同，则不满足背景的条件
-    for y in top..=bottom {
-        if img.get_pixel(left, y) != &color || img.get_pixel(right, y) != &color {
-            return false;
-        }
-    }
-    // 如果截图区域的上下两边的颜色有一个与左上角的颜色不同，则不满足背景的条件
-    for x in left..=right {
-        if img.get_pixel(x, top) != &color || img.get_pixel(x, bottom) != &color {
-            return false;
-        }
-    }
-    // 统计rect_data区域内color颜色的像素点数量
-    let color_count = img
-        .enumerate_pixels()
-        .filter(|(x, y, &pixel)| inside_rect(*x, *y) && pixel == color)
+fn check_background(
+    img: &RgbImage,
+    rect_data: &RectData,
+    thresholds: BackgroundThresholds,
+) -> Result<BackgroundStats, Rejection> {
+    let border: Vec<[u8; 3]> = rect_border_pixels(rect_data)
+        .map(|(x, y)| img.get_pixel(x, y).0)
+        .collect();
+    let color = median_color(&border);
+    let is_background_color =
+        |pixel: &[u8; 3]| is_similar_color(*pixel, color, thresholds.color_tolerance);
+    // 如果截图区域的边上有太多像素与背景颜色不同，则不满足背景的条件
+    let solid_count = border
+        .iter()
+        .filter(|pixel| is_background_color(pixel))
+        .count();
+    let solid_ratio = solid_count as f32 / border.len() as f32;
+    if solid_ratio < thresholds.min_solid_border_ratio {
+        return Err(Rejection::BorderNotSolid { solid_ratio });
+    }
+    // 如果截图区域内是背景颜色的像素太多，说明没有水印，则不满足背景的条件
+    let plain_count = rect_pixels(rect_data)
+        .filter(|&(x, y)| is_background_color(&img.get_pixel(x, y).0))
         .count();
-    // 如果rect_data区域内的像素点数量大于总数的90%，则不满足背景的条件
-    if color_count as f32 / ((right - left + 1) * (bottom - top + 1)) as f32 > 0.9 {
-        return false;
+    let rect_size = (rect_data.right - rect_data.left + 1) * (rect_data.bottom - rect_data.top + 1);
+    let plain_ratio = plain_count as f32 / rect_size as f32;
+    if plain_ratio > thresholds.max_plain_ratio {
+        return Err(Rejection::NoWatermark { plain_ratio });
+    }
+    let deviation: u32 = border
+        .iter()
+        .flat_map(|pixel| (0..3).map(|i| u32::from(pixel[i].abs_diff(color[i]))))
+        .sum();
+    Ok(BackgroundStats {
+        color,
+        noise: deviation as f32 / (border.len() * 3) as f32,
+        coverage: 1.0 - plain_ratio,
+    })
+}
+
+/// 颜色`a`和`b`每个通道的差距是否都不超过`tolerance`
+fn is_similar_color(a: [u8; 3], b: [u8; 3], tolerance: u8) -> bool {
+    (0..3).all(|i| a[i].abs_diff(b[i]) <= tolerance)
+}
+
+/// 用直方图求`pixels`每个通道的中位数
+#[allow(clippy::cast_possible_truncation)]
+fn median_color(pixels: &[[u8; 3]]) -> [u8; 3] {
+    [0, 1, 2].map(|channel| {
+        let mut histogram = [0_usize; 256];
+        for pixel in pixels {
+            histogram[usize::from(pixel[channel])] += 1;
+        }
+        let mut count = 0;
+        let half = pixels.len().div_ceil(2);
+        histogram
+            .iter()
+            .position(|&n| {
+                count += n;
+                count >= half
+            })
+            .unwrap_or(0) as u8
+    })
+}
+
+/// 汇总所有图片不满足背景条件的原因，每种原因给出数量和最接近满足条件的那张图片
+fn rejection_summary(rejections: &[RejectedImage]) -> String {
+    let mut summary = String::new();
+    let border_not_solid = rejections
+        .iter()
+        .filter_map(|(path, rejection)| match rejection {
+            Rejection::BorderNotSolid { solid_ratio } => Some((path, *solid_ratio)),
+            Rejection::NoWatermark { .. } => None,
+        });
+    let count = border_not_solid.clone().count();
+    if let Some((path, solid_ratio)) = border_not_solid.max_by(|(_, a), (_, b)| a.total_cmp(b)) {
+        summary += &format!(
+            "有{count}张图片截图区域的边上不是纯色，其中最接近的 {path:?} 边上有{:.1}%的像素是背景颜色\n",
+            solid_ratio * 100.0
+        );
+    }
+    let no_watermark = rejections
+        .iter()
+        .filter_map(|(path, rejection)| match rejection {
+            Rejection::NoWatermark { plain_ratio } => Some((path, *plain_ratio)),
+            Rejection::BorderNotSolid { .. } => None,
+        });
+    let count = no_watermark.clone().count();
+    if let Some((path, plain_ratio)) = no_watermark.min_by(|(_, a), (_, b)| a.total_cmp(b)) {
+        summary += &format!(
+            "有{count}张图片截图区域内没有水印，其中最接近的 {path:?} 截图区域内有{:.1}%的像素是背景颜色\n",
+            plain_ratio * 100.0
+        );
+    }
+    summary
+}
+
+/// 平均后的背景水印图
+struct Background {
+    color: [u8; 3],
+    /// 参与平均的图片数量
+    count: u32,
+    img: RgbImage,
+}
+
+/// 把`backgrounds`按照水印分组，同一组的背景水印图的背景颜色不同，但水印相同
+///
+/// 水印在不同背景颜色上的样子不同，但水印覆盖的位置相同  
+/// 所以用每个像素与背景颜色的差距作为水印的特征，特征的相关系数足够高时认为是同一种水印  
+/// 每组按照图片数量降序排列，组与组之间也按照图片数量降序排列
+fn group_by_watermark<'a>(
+    backgrounds: &'a [Background],
+    rect_data: &RectData,
+) -> Vec<Vec<&'a Background>> {
+    let signatures: Vec<Vec<f32>> = backgrounds
+        .iter()
+        .map(|background| watermark_signature(background, rect_data))
+        .collect();
+    let mut order: Vec<usize> = (0..backgrounds.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(backgrounds[i].count));
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for i in order {
+        // 同一组内的背景颜色各不相同，加入相关系数最高的组
+        let best_group = groups
+            .iter_mut()
+            .filter(|group| {
+                group
+                    .iter()
+                    .all(|&j| backgrounds[j].color != backgrounds[i].color)
+            })
+            .map(|group| {
+                let correlation = correlation(&signatures[group[0]], &signatures[i]);
+                (group, correlation)
+            })
+            .filter(|(_, correlation)| *correlation >= MIN_WATERMARK_CORRELATION)
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        match best_group {
+            Some((group, _)) => group.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+
+    let mut groups: Vec<Vec<&Background>> = groups
+        .into_iter()
+        .map(|group| group.into_iter().map(|i| &backgrounds[i]).collect())
+        .collect();
+    groups.sort_by_key(|group| {
+        let count: u32 = group.iter().map(|background| background.count).sum();
+        std::cmp::Reverse(count)
+    });
+    groups
+}
+
+/// 截图区域内每个像素与背景颜色的差距，作为水印的特征
+fn watermark_signature(background: &Background, rect_data: &RectData) -> Vec<f32> {
+    rect_pixels(rect_data)
+        .map(|(x, y)| {
+            let pixel = background.img.get_pixel(x, y).0;
+            (0..3)
+                .map(|i| f32::from(pixel[i].abs_diff(background.color[i])))
+                .sum()
+        })
+        .collect()
+}
+
+/// 计算`a`和`b`的皮尔逊相关系数
+#[allow(clippy::cast_precision_loss)]
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        let (da, db) = (a - mean_a, b - mean_b);
+        covariance += da * db;
+        variance_a += da * da;
+        variance_b += db * db;
+    }
+    if variance_a == 0.0 || variance_b == 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_a * variance_b).sqrt()
+}
+
+/// 从同一种水印的背景水印图`group`中找出每个通道的颜色差距都不小于`MIN_LEVEL_DIFFERENCE`的两张，返回(较暗的, 较亮的)
+///
+/// 有多对满足条件时，选择最小通道差距最大的一对
+fn find_background_pair<'a>(group: &[&'a Background]) -> Option<(&'a Background, &'a Background)> {
+    let mut best_pair = None;
+    let mut best_difference = MIN_LEVEL_DIFFERENCE;
+    for (i, &a) in group.iter().enumerate() {
+        for &b in &group[i + 1..] {
+            // 用差距最小的通道来衡量两种背景颜色的差距
+            let difference = (0..3)
+                .map(|channel| a.color[channel].abs_diff(b.color[channel]))
+                .min()
+                .unwrap_or(0);
+            if difference < best_difference {
+                continue;
+            }
+            best_difference = difference;
+            best_pair = if luma(a.color) <= luma(b.color) {
+                Some((a, b))
+            } else {
+                Some((b, a))
+            };
+        }
+    }
+    best_pair
+}
+
+/// 计算颜色`color`的亮度
+fn luma(color: [u8; 3]) -> f32 {
+    let [r, g, b] = color.map(f32::from);
+    0.299 * r + 0.587 * g + 0.114 * b
+}
+
+/// 删除`output_dir`中之前生成的所有背景水印图，避免残留的旧水印被当作新生成的
+fn remove_background_variants(output_dir: &Path) -> anyhow::Result<()> {
+    for index in 0.. {
+        let (black_filename, white_filename) = utils::get_background_filenames(index);
+        let black_path = output_dir.join(black_filename);
+        let white_path = output_dir.join(white_filename);
+        if !black_path.exists() && !white_path.exists() {
+            break;
+        }
+        for path in [black_path, white_path] {
+            if path.exists() {
+                std::fs::remove_file(&path).context(format!("删除图片 {path:?} 失败"))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 同一种背景颜色且同一种水印的背景水印图的累加器
+///
+/// 背景水印图来自JPEG压缩过的图片，单张图片的噪点会原样进入每张去水印后的图片  
+/// 所以把所有同色的背景水印图在截图区域内逐像素累加，最后取平均值来降低噪点
+struct BackgroundAccumulator {
+    count: u32,
+    /// 截图区域内每个像素每个通道的累加值，排列方式与`RgbImage`的数据一致
+    sum: Vec<u32>,
+}
+
+impl BackgroundAccumulator {
+    fn new(rect_data: &RectData) -> Self {
+        let rect_width = rect_data.right - rect_data.left + 1;
+        let rect_height = rect_data.bottom - rect_data.top + 1;
+        Self {
+            count: 0,
+            sum: vec![0; (rect_width * rect_height * 3) as usize],
+        }
+    }
+
+    /// 截图区域内的像素`patch`与当前平均值的平均差距，`patch`由`rect_patch`取出
+    #[allow(clippy::cast_precision_loss)]
+    fn mean_difference(&self, patch: &[u8]) -> f32 {
+        let mut difference = 0;
+        for (sum, &value) in self.sum.iter().zip(patch) {
+            let mean = (sum + self.count / 2) / self.count;
+            difference += mean.abs_diff(u32::from(value));
+        }
+        difference as f32 / self.sum.len() as f32
+    }
+
+    /// 把截图区域内的像素`patch`累加进来，`patch`由`rect_patch`取出
+    fn add(&mut self, patch: &[u8]) {
+        for (sum, &value) in self.sum.iter_mut().zip(patch) {
+            *sum += u32::from(value);
+        }
+        self.count += 1;
+    }
+
+    /// 生成尺寸为`width`x`height`的背景水印图，截图区域内为平均值，截图区域外为背景颜色`color`
+    #[allow(clippy::cast_possible_truncation)]
+    fn to_background(
+        &self,
+        color: [u8; 3],
+        width: u32,
+        height: u32,
+        rect_data: &RectData,
+    ) -> RgbImage {
+        let mut img = RgbImage::from_pixel(width, height, Rgb(color));
+        for (sum, (x, y)) in self.sum.chunks_exact(3).zip(rect_pixels(rect_data)) {
+            // 四舍五入取平均值
+            let pixel = [0, 1, 2].map(|i| ((sum[i] + self.count / 2) / self.count) as u8);
+            img.put_pixel(x, y, Rgb(pixel));
+        }
+        img
+    }
+}
+
+/// 按行取出图片`img`截图区域内所有像素的数据，排列方式与`RgbImage`的数据一致
+fn rect_patch(img: &RgbImage, rect_data: &RectData) -> Vec<u8> {
+    rect_pixels(rect_data)
+        .flat_map(|(x, y)| img.get_pixel(x, y).0)
+        .collect()
+}
+
+/// 遍历截图区域的边上所有像素的坐标，每个像素只出现一次
+fn rect_border_pixels(rect_data: &RectData) -> impl Iterator<Item = (u32, u32)> + '_ {
+    let (left, top, right, bottom) = (
+        rect_data.left,
+        rect_data.top,
+        rect_data.right,
+        rect_data.bottom,
+    );
+    rect_pixels(rect_data).filter(move |&(x, y)| x == left || x == right || y == top || y == bottom)
+}
+
+/// 按行遍历截图区域内所有像素的坐标
+fn rect_pixels(rect_data: &RectData) -> impl Iterator<Item = (u32, u32)> + '_ {
+    (rect_data.top..=rect_data.bottom)
+        .flat_map(move |y| (rect_data.left..=rect_data.right).map(move |x| (x, y)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn group_by_watermark_orders_groups_and_members_by_count() {
+        let rect_data = RectData {
+            left: 10,
+            top: 5,
+            right: 29,
+            bottom: 24,
+        };
+        // 两种水印分别覆盖截图区域的左半边和右半边，在不同的背景颜色上都混入50%的灰色
+        let left_mark = |x: u32, y: u32| (11..=19).contains(&x) && (6..=23).contains(&y);
+        let right_mark = |x: u32, y: u32| (20..=28).contains(&x) && (6..=23).contains(&y);
+        let watermarked = |level: u8, mark: &dyn Fn(u32, u32) -> bool| {
+            RgbImage::from_fn(40, 30, |x, y| {
+                let value = if mark(x, y) {
+                    (f32::from(level) + 128.0) / 2.0
+                } else {
+                    f32::from(level)
+                };
+                Rgb([value.round() as u8; 3])
+            })
+        };
+        let backgrounds = [
+            Background {
+                color: [20; 3],
+                count: 3,
+                img: watermarked(20, &left_mark),
+            },
+            Background {
+                color: [235; 3],
+                count: 5,
+                img: watermarked(235, &left_mark),
+            },
+            Background {
+                color: [20; 3],
+                count: 10,
+                img: watermarked(20, &right_mark),
+            },
+            Background {
+                color: [235; 3],
+                count: 1,
+                img: watermarked(235, &right_mark),
+            },
+            // 与第一张的背景颜色和水印都相同，同一组内的背景颜色不能重复，所以单独成组
+            Background {
+                color: [20; 3],
+                count: 2,
+                img: watermarked(20, &left_mark),
+            },
+        ];
+        let groups = group_by_watermark(&backgrounds, &rect_data);
+        let counts: Vec<Vec<u32>> = groups
+            .iter()
+            .map(|group| group.iter().map(|background| background.count).collect())
+            .collect();
+        // 右半边的水印共11张排在最前，组内也按照图片数量降序排列
+        assert_eq!(counts, vec![vec![10, 1], vec![5, 3], vec![2]]);
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn check_background_accepts_images_at_thresholds() {
+        // 20x20的截图区域，边上有76个像素
+        let rect_data = RectData {
+            left: 10,
+            top: 5,
+            right: 29,
+            bottom: 24,
+        };
+        let base = [200, 180, 160];
+        let img = RgbImage::from_fn(40, 30, |x, y| match (x, y) {
+            // 边上有一个像素不是背景颜色，75/76刚好不少于98%
+            (10, 5) => Rgb([0; 3]),
+            // 差距不超过color_tolerance的噪点仍然是背景颜色
+            (29, 24) => Rgb([208, 188, 168]),
+            // 水印覆盖40个像素，加上边上的那个像素，是背景颜色的像素刚好不超过90%
+            (12..=19, 8..=12) => Rgb([128; 3]),
+            _ => Rgb(base),
+        });
+
+        let Ok(stats) = check_background(&img, &rect_data, BackgroundThresholds::default()) else {
+            panic!("图片应该满足背景条件");
+        };
+        assert_eq!(stats.color, base);
+        assert!((stats.coverage - 41.0 / 400.0).abs() < 1e-6);
+        // 与背景颜色的差距之和为200 + 180 + 160 + 8 * 3
+        assert!((stats.noise - 564.0 / (76.0 * 3.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn check_background_rejects_border_that_is_not_solid() {
+        let rect_data = RectData {
+            left: 10,
+            top: 5,
+            right: 29,
+            bottom: 24,
+        };
+        let img = RgbImage::from_fn(40, 30, |x, y| match (x, y) {
+            // 边上有两个像素不是背景颜色，其中一个只有一个通道相差9，74/76少于98%
+            (10, 5) => Rgb([0; 3]),
+            (29, 24) => Rgb([209, 180, 160]),
+            (12..=19, 8..=12) => Rgb([128; 3]),
+            _ => Rgb([200, 180, 160]),
+        });
+
+        let result = check_background(&img, &rect_data, BackgroundThresholds::default());
+        let Err(Rejection::BorderNotSolid { solid_ratio }) = result else {
+            panic!("截图区域的边上应该不是纯色");
+        };
+        assert!((solid_ratio - 74.0 / 76.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn check_background_rejects_rect_without_watermark() {
+        let rect_data = RectData {
+            left: 10,
+            top: 5,
+            right: 29,
+            bottom: 24,
+        };
+        let img = RgbImage::from_fn(40, 30, |x, y| match (x, y) {
+            // 水印只覆盖39个像素，是背景颜色的像素有361/400，超过90%
+            (12..=19, 8..=12) if (x, y) != (19, 12) => Rgb([128; 3]),
+            _ => Rgb([200, 180, 160]),
+        });
+
+        let result = check_background(&img, &rect_data, BackgroundThresholds::default());
+        let Err(Rejection::NoWatermark { plain_ratio }) = result else {
+            panic!("截图区域内应该没有水印");
+        };
+        assert!((plain_ratio - 361.0 / 400.0).abs() < 1e-6);
     }
-    true
 }
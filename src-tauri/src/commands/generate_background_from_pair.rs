@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use parking_lot::RwLock;
+use tauri::{AppHandle, State};
+
+use crate::commands::generate_background::save_background_pair;
+use crate::config::Config;
+use crate::errors::CommandResult;
+use crate::types::RectData;
+use crate::utils;
+
+/// 用用户从候选图片中选出的一对图片`black_path`和`white_path`生成尺寸为`width`x`height`的背景水印图
+#[tauri::command(async)]
+#[specta::specta]
+#[allow(clippy::needless_pass_by_value)]
+#[allow(clippy::too_many_arguments)]
+pub fn generate_background_from_pair(
+    app: AppHandle,
+    config: State<RwLock<Config>>,
+    manga_dir: &str,
+    rect_data: RectData,
+    width: u32,
+    height: u32,
+    black_path: &str,
+    white_path: &str,
+) -> CommandResult<()> {
+    let thresholds = config.read().background_thresholds;
+    let output_dir = utils::get_background_dir_abs_path(&app, manga_dir, width, height)?;
+    save_background_pair(
+        &output_dir,
+        Path::new(black_path),
+        Path::new(white_path),
+        width,
+        height,
+        &rect_data,
+        thresholds,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use parking_lot::RwLock;
+use tauri::State;
+
+use crate::commands::generate_background::{background_candidates, create_image_paths};
+use crate::config::Config;
+use crate::errors::CommandResult;
+use crate::types::{BackgroundCandidate, RectData};
+
+/// 列出`manga_dir`目录下所有尺寸为`width`x`height`并且在截图区域`rect_data`处满足背景条件的图片，按照噪点从少到多排列
+#[tauri::command(async)]
+#[specta::specta]
+#[allow(clippy::needless_pass_by_value)]
+pub fn get_background_candidates(
+    config: State<RwLock<Config>>,
+    manga_dir: &str,
+    rect_data: RectData,
+    width: u32,
+    height: u32,
+) -> CommandResult<Vec<BackgroundCandidate>> {
+    let thresholds = config.read().background_thresholds;
+    let image_paths = create_image_paths(manga_dir, width, height);
+    let candidates = background_candidates(&image_paths, &rect_data, thresholds)?;
+    Ok(candidates)
+}
@@ -0,0 +1,34 @@
+use tauri::AppHandle;
+
+use crate::commands::open_image::open_image;
+use crate::errors::CommandResult;
+use crate::types::ImageData;
+use crate::utils;
+
+/// 获取尺寸为`width`x`height`的图片除第一种以外的其他水印的背景水印图，每种水印对应一对(黑色背景, 白色背景)
+#[tauri::command(async)]
+#[specta::specta]
+#[allow(clippy::needless_pass_by_value)]
+pub fn get_background_variants(
+    app: AppHandle,
+    manga_dir: &str,
+    width: u32,
+    height: u32,
+) -> CommandResult<Vec<(ImageData, ImageData)>> {
+    let background_dir = utils::get_background_dir_abs_path(&app, manga_dir, width, height)?;
+    let mut variants = vec![];
+    // 第0种水印是black.png和white.png，由前端单独加载
+    for index in 1.. {
+        let (black_filename, white_filename) = utils::get_background_filenames(index);
+        let black_background_path = background_dir.join(black_filename);
+        let white_background_path = background_dir.join(white_filename);
+        if !black_background_path.exists() || !white_background_path.exists() {
+            break;
+        }
+        let black_background = open_image(black_background_path.display().to_string())?;
+        let white_background = open_image(white_background_path.display().to_string())?;
+        variants.push((black_background, white_background));
+    }
+
+    Ok(variants)
+}
@@ -4,6 +4,7 @@ use std::path::PathBuf;
 use tauri::AppHandle;
 use walkdir::WalkDir;
 
+use crate::commands::get_background_variants::get_background_variants;
 use crate::commands::open_image::open_image;
 use crate::errors::CommandResult;
 use crate::types::MangaDirData;
@@ -26,8 +27,7 @@ pub fn get_manga_dir_data(app: AppHandle, manga_dir: &str) -> CommandResult<Vec<
             if !path.is_file() {
                 return None;
             }
-            let ext = path.extension()?.to_str()?.to_lowercase();
-            if ext != "jpg" && ext != "jpeg" {
+            if !utils::is_supported_image(&path) {
                 return None;
             }
             // imagesize::size(&path).ok()
@@ -46,10 +46,11 @@ pub fn get_manga_dir_data(app: AppHandle, manga_dir: &str) -> CommandResult<Vec<
             count,
             black_background: None,
             white_background: None,
+            variant_backgrounds: vec![],
         })
         .collect();
     // 以count降序排序
-    manga_dir_data.sort_by(|a, b| b.count.cmp(&a.count));
+    manga_dir_data.sort_by_key(|data| std::cmp::Reverse(data.count));
     // 获取背景水印图的数据
     for dir_data in &mut manga_dir_data {
         let width = dir_data.width;
@@ -67,6 +68,8 @@ pub fn get_manga_dir_data(app: AppHandle, manga_dir: &str) -> CommandResult<Vec<
             let white_background = open_image(white_background_path)?;
             dir_data.white_background = Some(white_background);
         }
+        dir_data.variant_backgrounds =
+            get_background_variants(app.clone(), manga_dir, width, height)?;
     }
 
     Ok(manga_dir_data)
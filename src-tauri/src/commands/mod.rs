@@ -1,19 +1,33 @@
 pub mod prelude {
     pub use crate::commands::{
-        generate_background::generate_background,
+        cancel_generate_background::cancel_generate_background,
+        detect_watermark_rect::detect_watermark_rect,
+        generate_background::{generate_background, GenerateBackgroundCancellation},
+        generate_background_from_pair::generate_background_from_pair,
+        get_background_candidates::get_background_candidates,
         get_background_dir_abs_path::get_background_dir_abs_path,
-        get_background_dir_relative_path::get_background_dir_relative_path, get_config::get_config,
-        get_jpg_image_infos::get_jpg_image_infos, get_manga_dir_data::get_manga_dir_data,
-        open_image::open_image, remove_watermark::remove_watermark, save_config::save_config,
+        get_background_dir_relative_path::get_background_dir_relative_path,
+        get_background_variants::get_background_variants,
+        get_config::get_config,
+        get_image_infos::get_image_infos,
+        get_manga_dir_data::get_manga_dir_data,
+        open_image::open_image,
+        remove_watermark::remove_watermark,
+        save_config::save_config,
         show_path_in_file_manager::show_path_in_file_manager,
     };
 }
 
+mod cancel_generate_background;
+mod detect_watermark_rect;
 mod generate_background;
+mod generate_background_from_pair;
+mod get_background_candidates;
 mod get_background_dir_abs_path;
 mod get_background_dir_relative_path;
+mod get_background_variants;
 mod get_config;
-mod get_jpg_image_infos;
+mod get_image_infos;
 mod get_manga_dir_data;
 mod open_image;
 mod remove_watermark;
@@ -1,44 +1,163 @@
 use std::collections::HashMap;
-use std::io::BufWriter;
+use std::io::Cursor;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Context};
 use image::codecs::png::PngEncoder;
-use image::{Rgb, RgbImage};
-use parking_lot::Mutex;
+use image::{
+    DynamicImage, GrayAlphaImage, GrayImage, ImageReader, LumaA, RgbImage, Rgba, RgbaImage,
+};
+use parking_lot::{Mutex, RwLock};
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use tauri::AppHandle;
+use tauri::{AppHandle, State};
 use tauri_specta::Event;
 use walkdir::WalkDir;
 
+use crate::commands::generate_background::generate_background_pairs;
+use crate::config::Config;
 use crate::errors::CommandResult;
 use crate::events;
-use crate::types::{ImageFormat, JpgImageData};
+use crate::metadata::Metadata;
+use crate::types::{
+    BackgroundThresholds, FlaggedImage, ImageData, ImageFormat, MetadataPolicy,
+    OversizedJpegPolicy, RectData, RemovalQuality, RemoveWatermarkReport,
+};
+use crate::utils;
+use crate::watermark;
+use crate::watermark::{Alignment, ModelOptions, Page, WatermarkModel, WatermarkRemoval};
+
+/// 去水印质量不达标时，在原本的搜索范围的基础上再扩大的平移量
+const FALLBACK_EXTRA_RADIUS: u32 = 2;
+/// 缩放后移植模型时尝试的最小缩放比例(百分比)
+const MIN_SCALE_PERCENT: u32 = 50;
+/// 缩放后移植模型时尝试的最大缩放比例(百分比)，100%的情况由按照右下角对齐移植的模型处理
+const MAX_SCALE_PERCENT: u32 = 95;
+/// 缩放后移植模型时尝试的缩放比例之间的间隔(百分比)
+const SCALE_PERCENT_STEP: usize = 5;
+/// JPEG的宽和高都用16位整数保存
+const MAX_JPEG_DIMENSION: u32 = u16::MAX as u32;
 
 #[tauri::command(async)]
 #[specta::specta]
 #[allow(clippy::needless_pass_by_value)]
 pub fn remove_watermark(
     app: AppHandle,
+    config: State<RwLock<Config>>,
     manga_dir: &str,
     output_dir: &str,
-    format: ImageFormat,
-    optimize: bool,
-    backgrounds_data: Vec<(JpgImageData, JpgImageData)>,
-) -> CommandResult<()> {
+    backgrounds_data: Vec<(ImageData, ImageData)>,
+) -> CommandResult<RemoveWatermarkReport> {
+    // 输出和去水印的选项从配置中读取，前端修改配置后会立即保存
+    let config = config.read().clone();
+    let format = config.output_format.clone();
+    let optimize = config.output_optimize;
+    let preserve_jpeg_blocks = config.preserve_jpeg_blocks;
+    let oversized_jpeg_policy = config.oversized_jpeg_policy;
+    let metadata_policy = config.metadata_policy;
     let manga_dir = PathBuf::from(manga_dir);
     let manga_dir_without_name = manga_dir
         .parent()
         .ok_or(anyhow!("漫画目录 {manga_dir:?} 的父目录不存在"))?;
     let output_dir = PathBuf::from(output_dir);
-    // (width, height) => (black, white)
-    let backgrounds = create_backgrounds(&backgrounds_data)?;
+    let model_options = ModelOptions {
+        inpaint_alpha_threshold: config.inpaint_alpha_threshold,
+        inversion_mode: config.inversion_mode,
+        deblocking: config.deblocking,
+    };
+    // (width, height) => [watermark_model1, watermark_model2, ...]
+    let backgrounds = create_backgrounds(&backgrounds_data, model_options)?;
+    // 与backgrounds一一对应的单通道模型，用于去除灰度图片的水印
+    let luma_backgrounds = create_luma_backgrounds(&backgrounds);
     // dir => [img_path1, img_path2, ...]
     let dir_map = create_dir_map(&manga_dir);
     // dir => (current, total)
     let dir_progress = create_dir_progress(&app, &dir_map)?;
     // 使用Mutex包装dir_progress，用于并发更新目录的进度
     let dir_progress = Mutex::new(dir_progress);
+    // 对齐水印的参数
+    let alignment = Alignment {
+        search_radius: config.alignment_search_radius,
+        subpixel: config.subpixel_alignment,
+    };
+    // 重新生成背景水印图时判断背景条件的阈值
+    let thresholds = config.background_thresholds;
+    // 彩色图片和灰度图片分别使用RGB模型和单通道模型
+    let rgb_source = ModelSource::new(
+        &backgrounds,
+        &dir_map,
+        model_options,
+        alignment,
+        thresholds,
+        false,
+    );
+    let luma_source = ModelSource::new(
+        &luma_backgrounds,
+        &dir_map,
+        model_options,
+        alignment,
+        thresholds,
+        true,
+    );
+    // 用于记录尺寸超过JPEG的上限的图片
+    let oversized_img_paths = Mutex::new(vec![]);
+    // 用于记录为了保留透明通道而以PNG输出的图片
+    let alpha_img_paths = Mutex::new(vec![]);
+    // 用于记录读取元数据失败的图片
+    let metadata_failed_img_paths = Mutex::new(vec![]);
+    // 保存去过水印的图片，`alpha`是图片的透明通道，`rect`是被修改过的区域
+    let save_removed_image = |page: &Page,
+                              alpha: Option<&GrayImage>,
+                              img_path: &Path,
+                              out_image_path: &Path,
+                              rect: &RectData| {
+        // 保留原图中的段时不需要读取元数据，所以只在重新编码整张图片时读取
+        let metadata = || match metadata_policy {
+            // 元数据读取失败不影响去水印，输出的图片不带元数据即可，完成后在报告中列出
+            MetadataPolicy::Preserve => read_metadata(img_path).unwrap_or_else(|_| {
+                metadata_failed_img_paths
+                    .lock()
+                    .push(img_path.to_path_buf());
+                Metadata::default()
+            }),
+            MetadataPolicy::Strip => Metadata::default(),
+        };
+        if let Some(alpha) = alpha {
+            // JPEG不支持透明通道，所以总是以PNG输出
+            if matches!(format, ImageFormat::Jpeg) {
+                alpha_img_paths.lock().push(img_path.to_path_buf());
+            }
+            return save_png_image_with_alpha(page, alpha, out_image_path, optimize, &metadata())
+                .context(format!("保存图片 {out_image_path:?} 失败"));
+        }
+        if matches!(format, ImageFormat::Jpeg) && !fits_in_jpeg(page) {
+            oversized_img_paths.lock().push(img_path.to_path_buf());
+            return save_oversized_image(
+                page,
+                out_image_path,
+                oversized_jpeg_policy,
+                optimize,
+                &metadata(),
+            )
+            .context(format!("保存图片 {out_image_path:?} 失败"));
+        }
+        if preserve_jpeg_blocks
+            && matches!(format, ImageFormat::Jpeg)
+            && save_jpg_preserving_blocks(page, img_path, out_image_path, rect, metadata_policy)?
+        {
+            return Ok(());
+        }
+        save_image(page, out_image_path, &format, optimize, &metadata())
+            .context(format!("保存图片 {out_image_path:?} 失败"))
+    };
+    // 用于记录尺寸匹配但检测不到水印而被跳过的图片
+    let skipped_img_paths = Mutex::new(vec![]);
+    // 用于记录去水印质量不达标的图片
+    let flagged_images = Mutex::new(vec![]);
+    // 用于记录经过自动重试得到更好结果的图片
+    let retried_img_paths = Mutex::new(vec![]);
+    // 用于记录借用其他尺寸的去水印模型去除水印的图片
+    let transplanted_img_paths = Mutex::new(vec![]);
     // 使用rayon的并行迭代器，并行处理每个目录
     let dir_map = dir_map.par_iter();
     dir_map.try_for_each(|entry| -> anyhow::Result<()> {
@@ -54,27 +173,71 @@ pub fn remove_watermark(
                 ))?;
             // 构建输出图片的路径(输出目录/漫画名/章节名/图片名)
             let out_image_path = output_dir.join(relative_path);
+            // 图片的去水印质量，图片被原样复制时为None
+            let mut quality = None;
             // 获取图片的尺寸
             let (width, height) = image::image_dimensions(img_path)
                 .context(format!("获取图片 {img_path:?} 的尺寸失败"))?;
-            if let Some((black, white)) = backgrounds.get(&(width, height)) {
-                // 在backgrounds中找到了黑色背景和白色背景的水印图片，可以去除水印
-                let mut img = image::open(img_path)
-                    .context(format!("打开图片 {img_path:?} 失败"))?
-                    .to_rgb8();
-
-                remove_image_watermark(black, white, &mut img);
-
-                save_image(&img, &out_image_path, &format, optimize)
-                    .context(format!("保存图片 {out_image_path:?} 失败"))?;
+            if backgrounds.contains_key(&(width, height)) {
+                // 在backgrounds中找到了对应尺寸的去水印模型，可以去除水印
+                let (mut page, alpha) = open_page(img_path)?;
+                let source = if page.is_luma() {
+                    &luma_source
+                } else {
+                    &rgb_source
+                };
+                let models = &source.backgrounds[&(width, height)];
+                let regenerate_models = || source.regenerator.models(dir, models);
+                if let Some(outcome) =
+                    remove_image_watermark(&mut page, models, alignment, false, regenerate_models)?
+                {
+                    if outcome.retried {
+                        retried_img_paths.lock().push(img_path.clone());
+                    }
+                    if !outcome.quality.is_acceptable() {
+                        flagged_images.lock().push(FlaggedImage {
+                            img_path: img_path.clone(),
+                            quality: outcome.quality,
+                        });
+                    }
+                    quality = Some(outcome.quality);
+                    save_removed_image(
+                        &page,
+                        alpha.as_ref(),
+                        img_path,
+                        &out_image_path,
+                        &outcome.rect,
+                    )?;
+                } else {
+                    // 检测不到水印(比如封面、已经去过水印的图片)，强行去水印会破坏图片，所以直接复制
+                    copy_image(img_path, &out_image_path, metadata_policy)?;
+                    skipped_img_paths.lock().push(img_path.clone());
+                }
+            } else if config.transplant_models && !backgrounds.is_empty() {
+                // 没有对应尺寸的去水印模型(比如章节的最后一页、被缩小过的图片)，借用其他尺寸的模型
+                // 借用的模型可能把不是水印的内容当成水印，开销也不小，所以只在配置开启时借用
+                let (mut page, alpha) = open_page(img_path)?;
+                let source = if page.is_luma() {
+                    &luma_source
+                } else {
+                    &rgb_source
+                };
+                if let Some(outcome) = source.transplanter.remove_watermark(&mut page, alignment)? {
+                    transplanted_img_paths.lock().push(img_path.clone());
+                    quality = Some(outcome.quality);
+                    save_removed_image(
+                        &page,
+                        alpha.as_ref(),
+                        img_path,
+                        &out_image_path,
+                        &outcome.rect,
+                    )?;
+                } else {
+                    copy_image(img_path, &out_image_path, metadata_policy)?;
+                }
             } else {
                 // 否则，直接复制图片到输出目录
-                if let Some(parent) = out_image_path.parent() {
-                    // 保证输出目录存在
-                    std::fs::create_dir_all(parent).context(format!("创建目录 {parent:?} 失败"))?;
-                }
-                std::fs::copy(img_path, &out_image_path)
-                    .context(format!("复制图片 {img_path:?} 到 {out_image_path:?} 失败"))?;
+                copy_image(img_path, &out_image_path, metadata_policy)?;
             }
             // 更新目录的进度
             let (current, total) = {
@@ -90,6 +253,7 @@ pub fn remove_watermark(
                 dir_path: dir.clone(),
                 img_path: out_image_path.clone(),
                 current,
+                quality,
             };
             let event = events::RemoveWatermarkSuccessEvent(payload);
             event.emit(&app)?;
@@ -107,7 +271,362 @@ pub fn remove_watermark(
         Ok(())
     })?;
 
-    Ok(())
+    let mut skipped_img_paths = skipped_img_paths.into_inner();
+    skipped_img_paths.sort();
+    let mut flagged_images = flagged_images.into_inner();
+    flagged_images.sort_by(|a, b| a.img_path.cmp(&b.img_path));
+    let mut retried_img_paths = retried_img_paths.into_inner();
+    retried_img_paths.sort();
+    let mut transplanted_img_paths = transplanted_img_paths.into_inner();
+    transplanted_img_paths.sort();
+    let mut oversized_img_paths = oversized_img_paths.into_inner();
+    oversized_img_paths.sort();
+    let mut alpha_img_paths = alpha_img_paths.into_inner();
+    alpha_img_paths.sort();
+    let mut metadata_failed_img_paths = metadata_failed_img_paths.into_inner();
+    metadata_failed_img_paths.sort();
+    Ok(RemoveWatermarkReport {
+        skipped_img_paths,
+        flagged_images,
+        retried_img_paths,
+        transplanted_img_paths,
+        oversized_img_paths,
+        alpha_img_paths,
+        metadata_failed_img_paths,
+    })
+}
+
+/// 一张图片去水印的结果
+struct RemovalOutcome {
+    quality: RemovalQuality,
+    /// 是否经过自动重试得到了更好的结果
+    retried: bool,
+    /// 被修改过的区域
+    rect: RectData,
+}
+
+/// 去除图片`page`的水印，返回去水印的结果，检测不到水印时返回`None`
+///
+/// 同一尺寸可能有多种水印，先按照`alignment`对齐后用所有模型逐个尝试，选择质量最好的  
+/// 检测到水印但质量不达标时，扩大对齐的搜索范围再试一次，应对图片被裁剪得更多的情况  
+/// 质量仍然不达标时，用`regenerate_models`根据相邻章节重新生成的模型再次搜索  
+/// 最后保留所有尝试中质量最好的结果，`require_acceptable`为true时质量不达标的结果也视为检测不到水印
+fn remove_image_watermark(
+    page: &mut Page,
+    models: &[WatermarkModel],
+    alignment: Alignment,
+    require_acceptable: bool,
+    regenerate_models: impl FnOnce() -> anyhow::Result<SharedModels>,
+) -> anyhow::Result<Option<RemovalOutcome>> {
+    let regenerated_models;
+    let mut best = watermark::best_removal(models, page, alignment);
+    let mut retried = false;
+    let fallback_alignment = fallback_alignment(alignment);
+    let is_unacceptable = |best: &Option<WatermarkRemoval>| {
+        best.as_ref()
+            .is_some_and(|removal| !removal.quality.is_acceptable())
+    };
+
+    // 检测不到水印的图片(比如封面)很常见，扩大搜索范围和重新生成模型的开销都很大，所以只对检测到水印但质量不达标的图片重试
+    if is_unacceptable(&best) {
+        let shifted = watermark::best_removal(models, page, fallback_alignment);
+        retried |= keep_better(&mut best, shifted);
+    }
+    if is_unacceptable(&best) {
+        regenerated_models = regenerate_models()?;
+        let regenerated = watermark::best_removal(&regenerated_models, page, fallback_alignment);
+        retried |= keep_better(&mut best, regenerated);
+    }
+
+    let Some(removal) = best else {
+        return Ok(None);
+    };
+    if require_acceptable && !removal.quality.is_acceptable() {
+        return Ok(None);
+    }
+    let outcome = RemovalOutcome {
+        quality: removal.quality,
+        retried,
+        rect: *removal.rect(),
+    };
+    removal.apply(page);
+    Ok(Some(outcome))
+}
+
+/// 自动重试时使用的对齐参数，搜索范围比`alignment`大`FALLBACK_EXTRA_RADIUS`
+fn fallback_alignment(alignment: Alignment) -> Alignment {
+    Alignment {
+        search_radius: alignment.search_radius + FALLBACK_EXTRA_RADIUS,
+        ..alignment
+    }
+}
+
+/// `candidate`的质量比`best`更好时，用`candidate`替换`best`，返回是否发生了替换
+fn keep_better<'a>(
+    best: &mut Option<WatermarkRemoval<'a>>,
+    candidate: Option<WatermarkRemoval<'a>>,
+) -> bool {
+    let Some(candidate) = candidate else {
+        return false;
+    };
+    match best.take() {
+        Some(current) if !candidate.quality.is_better_than(current.quality) => {
+            *best = Some(current);
+            false
+        }
+        _ => {
+            *best = Some(candidate);
+            true
+        }
+    }
+}
+
+/// 在多个线程之间共享的去水印模型
+type SharedModels = Arc<Vec<WatermarkModel>>;
+
+/// 同一种通道数量的图片使用的所有去水印模型
+struct ModelSource<'a> {
+    /// (width, height) => [watermark_model1, watermark_model2, ...]
+    backgrounds: &'a HashMap<(u32, u32), Vec<WatermarkModel>>,
+    /// 用于在去水印质量不达标时，根据相邻章节重新生成去水印模型
+    regenerator: ModelRegenerator<'a>,
+    /// 用于给没有对应尺寸背景水印图的图片借用其他尺寸的去水印模型
+    transplanter: ModelTransplanter<'a>,
+}
+
+impl<'a> ModelSource<'a> {
+    fn new(
+        backgrounds: &'a HashMap<(u32, u32), Vec<WatermarkModel>>,
+        dir_map: &'a HashMap<PathBuf, Vec<PathBuf>>,
+        model_options: ModelOptions,
+        alignment: Alignment,
+        thresholds: BackgroundThresholds,
+        luma: bool,
+    ) -> Self {
+        Self {
+            backgrounds,
+            regenerator: ModelRegenerator::new(dir_map, model_options, alignment, thresholds, luma),
+            transplanter: ModelTransplanter::new(backgrounds),
+        }
+    }
+}
+
+/// 重新生成的去水印模型的缓存键，(目录, (width, height))
+type RegeneratedKey = (PathBuf, (u32, u32));
+
+/// 根据相邻章节中的背景图片重新生成去水印模型
+///
+/// 同一部漫画不同时期的水印可能略有不同，相邻章节的水印最接近，所以用图片所在目录及其前后两个目录中的图片重新生成  
+/// 重新生成的开销很大，所以按照(目录, 尺寸)缓存生成的模型
+struct ModelRegenerator<'a> {
+    dir_map: &'a HashMap<PathBuf, Vec<PathBuf>>,
+    /// 按照路径排序的所有目录，用于查找相邻章节
+    dirs: Vec<&'a PathBuf>,
+    /// 重新生成的模型使用的选项，与其他模型一致
+    model_options: ModelOptions,
+    /// 判断图片是否满足背景条件时使用的阈值
+    thresholds: BackgroundThresholds,
+    /// 是否把重新生成的模型转换为单通道模型，用于灰度图片
+    luma: bool,
+    /// 重新生成时截图区域向外扩展的像素数，保证自动重试时平移后的水印仍在截图区域内
+    margin: u32,
+    /// (dir, (width, height)) => [watermark_model1, watermark_model2, ...]
+    cache: Mutex<HashMap<RegeneratedKey, SharedModels>>,
+}
+
+impl<'a> ModelRegenerator<'a> {
+    fn new(
+        dir_map: &'a HashMap<PathBuf, Vec<PathBuf>>,
+        model_options: ModelOptions,
+        alignment: Alignment,
+        thresholds: BackgroundThresholds,
+        luma: bool,
+    ) -> Self {
+        let mut dirs: Vec<&PathBuf> = dir_map.keys().collect();
+        dirs.sort();
+        Self {
+            dir_map,
+            dirs,
+            model_options,
+            thresholds,
+            luma,
+            margin: fallback_alignment(alignment).search_radius + 1,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 获取目录`dir`下与`models`同一尺寸的图片的重新生成的模型，找不到背景图片时返回空的`Vec`
+    fn models(&self, dir: &Path, models: &[WatermarkModel]) -> anyhow::Result<SharedModels> {
+        let Some(first_model) = models.first() else {
+            return Ok(Arc::new(vec![]));
+        };
+        let (width, height) = first_model.dimensions();
+        let key = (dir.to_path_buf(), (width, height));
+        if let Some(regenerated_models) = self.cache.lock().get(&key) {
+            return Ok(regenerated_models.clone());
+        }
+        // 生成模型时不能持有锁，因为生成过程中rayon可能在当前线程上执行其他需要这把锁的任务
+        // 所以同一目录的模型可能被并发地重复生成，但结果相同，只保留先生成的
+        let image_paths = self.neighbour_image_paths(dir, width, height);
+        let rect_data = regenerate_rect(models, self.margin);
+        let regenerated_models: Vec<WatermarkModel> =
+            generate_background_pairs(&image_paths, width, height, &rect_data, self.thresholds)
+                .context(format!("根据目录 {dir:?} 的相邻章节重新生成背景水印图失败"))?
+                .iter()
+                .filter_map(|(black, white)| {
+                    WatermarkModel::new(black, white, self.model_options).ok()
+                })
+                .map(|model| if self.luma { model.to_luma() } else { model })
+                .collect();
+        let regenerated_models = self
+            .cache
+            .lock()
+            .entry(key)
+            .or_insert(Arc::new(regenerated_models))
+            .clone();
+        Ok(regenerated_models)
+    }
+
+    /// 收集目录`dir`及其前后两个目录中尺寸为`width`x`height`的图片的路径
+    fn neighbour_image_paths(&self, dir: &Path, width: u32, height: u32) -> Vec<PathBuf> {
+        let Some(index) = self.dirs.iter().position(|d| d.as_path() == dir) else {
+            return vec![];
+        };
+        let neighbour_dirs = &self.dirs[index.saturating_sub(1)..(index + 2).min(self.dirs.len())];
+        neighbour_dirs
+            .iter()
+            .flat_map(|dir| &self.dir_map[*dir])
+            .filter(|path| image::image_dimensions(path).is_ok_and(|size| size == (width, height)))
+            .cloned()
+            .collect()
+    }
+}
+
+/// 给没有对应尺寸背景水印图的图片借用其他尺寸的去水印模型
+///
+/// 水印固定在图片的右下角，所以先把其他尺寸的模型按照右下角对齐移植过来，宽度相同的尺寸最可能是同一种水印，排在前面  
+/// 移植的模型都不适用时，再尝试缩放后移植的模型，用于去除被缩小过的图片的水印  
+/// 移植和缩放的开销不小，所以按照尺寸缓存移植后的模型
+struct ModelTransplanter<'a> {
+    backgrounds: &'a HashMap<(u32, u32), Vec<WatermarkModel>>,
+    /// (width, height) => 按照右下角对齐移植的模型
+    anchored_cache: Mutex<HashMap<(u32, u32), SharedModels>>,
+    /// (width, height) => 缩放后移植的模型
+    scaled_cache: Mutex<HashMap<(u32, u32), SharedModels>>,
+}
+
+impl<'a> ModelTransplanter<'a> {
+    fn new(backgrounds: &'a HashMap<(u32, u32), Vec<WatermarkModel>>) -> Self {
+        Self {
+            backgrounds,
+            anchored_cache: Mutex::new(HashMap::new()),
+            scaled_cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 借用其他尺寸的模型去除`page`的水印，返回去水印的结果，没有模型适用于这张图片时返回`None`
+    ///
+    /// 借用的模型不一定适用于这张图片，所以质量必须达标才使用，也不根据相邻章节重新生成模型
+    fn remove_watermark(
+        &self,
+        page: &mut Page,
+        alignment: Alignment,
+    ) -> anyhow::Result<Option<RemovalOutcome>> {
+        let (width, height) = page.dimensions();
+        let regenerate_models = || Ok(Arc::default());
+        let anchored_models = self.anchored_models(width, height);
+        if let Some(outcome) =
+            remove_image_watermark(page, &anchored_models, alignment, true, regenerate_models)?
+        {
+            return Ok(Some(outcome));
+        }
+        let scaled_models = self.scaled_models(width, height);
+        remove_image_watermark(page, &scaled_models, alignment, true, regenerate_models)
+    }
+
+    /// 获取按照右下角对齐移植到尺寸为`width`x`height`的图片上的所有模型
+    fn anchored_models(&self, width: u32, height: u32) -> SharedModels {
+        cached_models(&self.anchored_cache, (width, height), || {
+            self.sorted_source_models(width)
+                .filter_map(|model| model.anchored_to(width, height))
+                .collect()
+        })
+    }
+
+    /// 获取缩放后移植到尺寸为`width`x`height`的图片上的所有模型
+    ///
+    /// 图片整体缩小时，宽度之比和高度之比就是水印的缩放比例，所以优先尝试这两个比例，再每隔`SCALE_PERCENT_STEP`尝试一个比例
+    #[allow(clippy::cast_precision_loss)]
+    fn scaled_models(&self, width: u32, height: u32) -> SharedModels {
+        cached_models(&self.scaled_cache, (width, height), || {
+            self.sorted_source_models(width)
+                .flat_map(|model| {
+                    let (model_width, model_height) = model.dimensions();
+                    let ratios = [
+                        width as f32 / model_width as f32,
+                        height as f32 / model_height as f32,
+                    ];
+                    let min_scale = MIN_SCALE_PERCENT as f32 / 100.0;
+                    let mut scales: Vec<f32> = ratios
+                        .into_iter()
+                        .filter(|ratio| (min_scale..1.0).contains(ratio))
+                        .chain(
+                            (MIN_SCALE_PERCENT..=MAX_SCALE_PERCENT)
+                                .step_by(SCALE_PERCENT_STEP)
+                                .map(|percent| percent as f32 / 100.0),
+                        )
+                        .collect();
+                    scales.dedup_by(|a, b| (*a - *b).abs() < f32::EPSILON);
+                    scales
+                        .into_iter()
+                        .filter_map(move |scale| model.scaled(scale, width, height))
+                })
+                .collect()
+        })
+    }
+
+    /// 按照宽度是否与`width`相同、尺寸的顺序遍历所有尺寸的模型，保证结果稳定
+    fn sorted_source_models(&self, width: u32) -> impl Iterator<Item = &'a WatermarkModel> {
+        let mut sizes: Vec<&(u32, u32)> = self.backgrounds.keys().collect();
+        sizes.sort_by_key(|&&(w, h)| (w != width, w, h));
+        let backgrounds = self.backgrounds;
+        sizes.into_iter().flat_map(move |size| &backgrounds[size])
+    }
+}
+
+/// 从`cache`中获取尺寸为`size`的模型，缓存中没有时用`build`生成后放入缓存
+///
+/// 与`ModelRegenerator::models`相同，生成模型时不持有锁，避免其他尺寸的图片等待这把锁  
+/// 所以同一尺寸的模型可能被并发地重复生成，但结果相同，只保留先生成的
+fn cached_models(
+    cache: &Mutex<HashMap<(u32, u32), SharedModels>>,
+    size: (u32, u32),
+    build: impl FnOnce() -> Vec<WatermarkModel>,
+) -> SharedModels {
+    if let Some(models) = cache.lock().get(&size) {
+        return models.clone();
+    }
+    let models = Arc::new(build());
+    cache.lock().entry(size).or_insert(models).clone()
+}
+
+/// 重新生成背景水印图时使用的截图区域，即所有模型的水印外接矩形的并集，再向外扩展`margin`个像素以容纳平移后的水印
+fn regenerate_rect(models: &[WatermarkModel], margin: u32) -> RectData {
+    let (width, height) = models[0].dimensions();
+    let mut rect = *models[0].rect();
+    for model in &models[1..] {
+        let other = model.rect();
+        rect.left = rect.left.min(other.left);
+        rect.top = rect.top.min(other.top);
+        rect.right = rect.right.max(other.right);
+        rect.bottom = rect.bottom.max(other.bottom);
+    }
+    RectData {
+        left: rect.left.saturating_sub(margin),
+        top: rect.top.saturating_sub(margin),
+        right: (rect.right + margin).min(width - 1),
+        bottom: (rect.bottom + margin).min(height - 1),
+    }
 }
 
 /// 构建一个`HashMap`，`key`是目录的路径，`value`是该目录下的所有jpg文件的路径
@@ -134,7 +653,7 @@ fn create_dir_progress<'a>(
     Ok(dir_progress)
 }
 
-/// 构建一个`HashMap`，`key`是目录的路径，`value`是该目录下的所有jpg文件的路径
+/// 构建一个`HashMap`，`key`是目录的路径，`value`是该目录下的所有图片的路径
 fn create_dir_map(manga_dir: &PathBuf) -> HashMap<PathBuf, Vec<PathBuf>> {
     let mut dir_map: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
     // 遍历manga_dir目录下的所有文件和子目录
@@ -146,8 +665,7 @@ fn create_dir_map(manga_dir: &PathBuf) -> HashMap<PathBuf, Vec<PathBuf>> {
             if !path.is_file() {
                 return None;
             }
-            let ext = path.extension()?.to_str()?.to_lowercase();
-            if ext != "jpg" && ext != "jpeg" {
+            if !utils::is_supported_image(&path) {
                 return None;
             }
             let parent = path.parent()?.to_path_buf();
@@ -157,104 +675,239 @@ fn create_dir_map(manga_dir: &PathBuf) -> HashMap<PathBuf, Vec<PathBuf>> {
     dir_map
 }
 
-/// 构建一个`HashMap`，`key`是背景水印图的尺寸，`value`是黑色背景和白色背景水印图
+/// 构建一个`HashMap`，`key`是背景水印图的尺寸，`value`是该尺寸的所有去水印模型
+///
+/// 同一尺寸的图片可能有多种水印，每对黑色背景和白色背景水印图对应一种水印
 fn create_backgrounds(
-    backgrounds_data: &[(JpgImageData, JpgImageData)],
-) -> anyhow::Result<HashMap<(u32, u32), (RgbImage, RgbImage)>> {
-    let backgrounds = backgrounds_data
-        .iter()
-        .map(|(black_data, white_data)| {
-            let black = black_data
-                .to_image()
-                .context(format!("黑色背景水印图 {:?} 转换失败", black_data.info.path))?
-                .to_rgb8();
-            let white = white_data
-                .to_image()
-                .context(format!("白色背景水印图 {:?} 转换失败", white_data.info.path))?
-                .to_rgb8();
-            if black.dimensions() != white.dimensions() {
-                return Err(anyhow!(
-                    "黑色背景和白色背景水印图的尺寸不一致，黑色背景水印图的尺寸是 ({}x{})，白色背景水印图的尺寸是 ({}x{})",
-                    black.width(),
-                    black.height(),
-                    white.width(),
-                    white.height(),
-                ));
-            }
-            Ok(((black.width(), black.height()), (black, white)))
-        })
-        .collect::<anyhow::Result<HashMap<(u32, u32), (RgbImage, RgbImage)>>>()?;
+    backgrounds_data: &[(ImageData, ImageData)],
+    model_options: ModelOptions,
+) -> anyhow::Result<HashMap<(u32, u32), Vec<WatermarkModel>>> {
+    let mut backgrounds: HashMap<(u32, u32), Vec<WatermarkModel>> = HashMap::new();
+    for (black_data, white_data) in backgrounds_data {
+        let black = black_data
+            .to_image()
+            .context(format!(
+                "黑色背景水印图 {:?} 转换失败",
+                black_data.info.path
+            ))?
+            .to_rgb8();
+        let white = white_data
+            .to_image()
+            .context(format!(
+                "白色背景水印图 {:?} 转换失败",
+                white_data.info.path
+            ))?
+            .to_rgb8();
+        let model = WatermarkModel::new(&black, &white, model_options).context(format!(
+            "根据背景水印图 {:?} 和 {:?} 构建去水印模型失败",
+            black_data.info.path, white_data.info.path
+        ))?;
+        backgrounds
+            .entry(model.dimensions())
+            .or_default()
+            .push(model);
+    }
     Ok(backgrounds)
 }
 
-/// 去除`img`的水印
-#[allow(clippy::cast_possible_truncation)]
-#[allow(clippy::cast_lossless)]
-#[allow(clippy::cast_sign_loss)]
-fn remove_image_watermark(black: &RgbImage, white: &RgbImage, img: &mut RgbImage) {
-    if img.width() != white.width() || img.height() != white.height() {
-        return;
-    }
-    // 遍历图片的每个像素点
-    let [black_in_r, black_in_g, black_in_b] = black.get_pixel(0, 0).0.map(|x| x as f64);
-    for (x, y, img_pixel) in img.enumerate_pixels_mut() {
-        let [out_r, out_g, out_b] = img_pixel.0.map(|x| x as f64);
-        let [black_out_r, black_out_g, black_out_b] = black.get_pixel(x, y).0.map(|x| x as f64);
-        let [white_out_r, white_out_g, white_out_b] = white.get_pixel(x, y).0.map(|x| x as f64);
-
-        let in_r = (out_r - black_out_r) / ((white_out_r - black_out_r) / 255.0) + black_in_r;
-        let in_g = (out_g - black_out_g) / ((white_out_g - black_out_g) / 255.0) + black_in_g;
-        let in_b = (out_b - black_out_b) / ((white_out_b - black_out_b) / 255.0) + black_in_b;
-        // 将f64转换为u8自带clamp功能
-        let watermark_removed_r = in_r.round() as u8;
-        let watermark_removed_g = in_g.round() as u8;
-        let watermark_removed_b = in_b.round() as u8;
-        // 将去除水印后的像素点赋值给img
-        *img_pixel = Rgb([
-            watermark_removed_r,
-            watermark_removed_g,
-            watermark_removed_b,
-        ]);
-    }
-}
-
-/// 保存图片`img`到指定路径`path`，`format`为图片格式，`optimize`为true时会检查图片是否为灰度图像，如果是则保存为luma8图片
-#[allow(clippy::cast_possible_truncation)]
+/// 把`backgrounds`中的每个模型转换为单通道模型，用于去除灰度图片的水印
+fn create_luma_backgrounds(
+    backgrounds: &HashMap<(u32, u32), Vec<WatermarkModel>>,
+) -> HashMap<(u32, u32), Vec<WatermarkModel>> {
+    backgrounds
+        .iter()
+        .map(|(size, models)| (*size, models.iter().map(WatermarkModel::to_luma).collect()))
+        .collect()
+}
+
+/// 打开图片`img_path`，返回(图片, 透明通道)，灰度图片保持单通道，其他图片转换为RGB，完全不透明的图片没有透明通道
+///
+/// 条漫的图片可能非常长，解码需要的内存会超过`image`默认的上限，所以不限制内存  
+/// 彩色的JPEG同时记录原图的色度采样块
+fn open_page(img_path: &Path) -> anyhow::Result<(Page, Option<GrayImage>)> {
+    let data = std::fs::read(img_path).context(format!("打开图片 {img_path:?} 失败"))?;
+    let mut reader = ImageReader::new(Cursor::new(&data))
+        .with_guessed_format()
+        .context(format!("识别图片 {img_path:?} 的格式失败"))?;
+    reader.no_limits();
+    let img = reader
+        .decode()
+        .context(format!("解码图片 {img_path:?} 失败"))?;
+    let alpha = alpha_channel(&img);
+    let mut page = Page::from(img);
+    if let Page::Rgb(_, subsampling) = &mut page {
+        if let Some(source_subsampling) = crate::jpeg::chroma_subsampling(&data) {
+            *subsampling = source_subsampling;
+        }
+    }
+    Ok((page, alpha))
+}
+
+/// 获取图片`img`的透明通道，图片没有透明通道或者完全不透明时返回`None`
+fn alpha_channel(img: &DynamicImage) -> Option<GrayImage> {
+    if !img.color().has_alpha() {
+        return None;
+    }
+    let rgba = img.to_rgba8();
+    if rgba.pixels().all(|pixel| pixel[3] == u8::MAX) {
+        return None;
+    }
+    let alpha = rgba.pixels().map(|pixel| pixel[3]).collect();
+    GrayImage::from_raw(rgba.width(), rgba.height(), alpha)
+}
+
+/// 读取图片`img_path`中重新编码时需要保留的元数据
+fn read_metadata(img_path: &Path) -> anyhow::Result<Metadata> {
+    let data = std::fs::read(img_path).context(format!("读取图片 {img_path:?} 失败"))?;
+    Metadata::read(&data).context(format!("读取图片 {img_path:?} 的元数据失败"))
+}
+
+/// 把图片`img_path`原样复制到`out_image_path`，`metadata_policy`为`Strip`时删除图片中的元数据
+///
+/// 无法识别的格式即使要删除元数据也原样复制
+fn copy_image(
+    img_path: &Path,
+    out_image_path: &Path,
+    metadata_policy: MetadataPolicy,
+) -> anyhow::Result<()> {
+    if let Some(parent) = out_image_path.parent() {
+        // 保证输出目录存在
+        std::fs::create_dir_all(parent).context(format!("创建目录 {parent:?} 失败"))?;
+    }
+    if matches!(metadata_policy, MetadataPolicy::Strip) {
+        let data = std::fs::read(img_path).context(format!("读取图片 {img_path:?} 失败"))?;
+        if let Some(stripped) = crate::metadata::strip(&data) {
+            std::fs::write(out_image_path, stripped)
+                .context(format!("保存图片 {out_image_path:?} 失败"))?;
+            return Ok(());
+        }
+    }
+    std::fs::copy(img_path, out_image_path)
+        .context(format!("复制图片 {img_path:?} 到 {out_image_path:?} 失败"))?;
+    Ok(())
+}
+
+/// 保存图片`page`到指定路径`path`，`format`为图片格式，`metadata`为写入图片的元数据
+///
+/// 灰度图片直接保存为luma8图片，RGB图片在`optimize`为true时会检查是否为灰度图像，如果是则保存为luma8图片
 fn save_image(
-    img: &RgbImage,
+    page: &Page,
     path: &Path,
     format: &ImageFormat,
     optimize: bool,
+    metadata: &Metadata,
 ) -> anyhow::Result<()> {
     // 保证输出目录存在
     if let Some(parent) = path.parent() {
         std::fs::create_dir_all(parent).context(format!("创建目录 {parent:?} 失败"))?;
     }
 
-    match format {
-        ImageFormat::Jpeg => {
-            save_jpg_image(img, path, optimize)?;
+    match (format, page) {
+        (ImageFormat::Jpeg, Page::Rgb(img, _)) => {
+            save_jpg_image(img, path, optimize, metadata)?;
+        }
+        (ImageFormat::Jpeg, Page::Luma(img)) => {
+            save_luma_jpg_image(img, path, metadata)?;
+        }
+        (ImageFormat::Png, Page::Rgb(img, _)) => {
+            save_png_image(img, path, optimize, metadata)?;
         }
-        ImageFormat::Png => {
-            save_png_image(img, path, optimize)?;
+        (ImageFormat::Png, Page::Luma(img)) => {
+            save_luma_png_image(img, path, metadata)?;
         }
     }
     Ok(())
 }
 
+/// 图片的尺寸是否在JPEG的上限以内
+fn fits_in_jpeg(page: &Page) -> bool {
+    let (width, height) = page.dimensions();
+    width <= MAX_JPEG_DIMENSION && height <= MAX_JPEG_DIMENSION
+}
+
+/// 按照`policy`保存尺寸超过JPEG的上限的图片`page`
+///
+/// 切分时把图片按照高度平均切分为尽量少的几段，每段保存为`图片名_01.jpg`、`图片名_02.jpg`……  
+/// 宽度超过上限时无法按照高度切分，仍以PNG输出
+fn save_oversized_image(
+    page: &Page,
+    path: &Path,
+    policy: OversizedJpegPolicy,
+    optimize: bool,
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
+    let (width, height) = page.dimensions();
+    if matches!(policy, OversizedJpegPolicy::Png) || width > MAX_JPEG_DIMENSION {
+        return save_image(page, path, &ImageFormat::Png, optimize, metadata);
+    }
+    let segment_count = height.div_ceil(MAX_JPEG_DIMENSION);
+    let segment_height = height.div_ceil(segment_count);
+    let stem = path
+        .file_stem()
+        .ok_or(anyhow!("{path:?} 没有文件名"))?
+        .to_string_lossy();
+    for index in 0..segment_count {
+        let top = index * segment_height;
+        let segment = page.crop_rows(top, segment_height.min(height - top));
+        let segment_path = path.with_file_name(format!("{stem}_{:02}.jpg", index + 1));
+        save_image(
+            &segment,
+            &segment_path,
+            &ImageFormat::Jpeg,
+            optimize,
+            metadata,
+        )
+        .context(format!("保存第{}段图片 {segment_path:?} 失败", index + 1))?;
+    }
+    Ok(())
+}
+
+/// 把去过水印的图片`page`保存为jpg，只重新编码原图`img_path`中与`rect`相交的块，其他块保留原图的DCT系数
+///
+/// 原图中的段原样保留，`metadata_policy`为`Strip`时再删除其中的元数据  
+/// 原图的JPEG类型不支持或者原图有这里无法处理的错误(例如缺少EOI、RST标记)时不保存，返回false，由调用者用普通的方式保存
+fn save_jpg_preserving_blocks(
+    page: &Page,
+    img_path: &Path,
+    path: &Path,
+    rect: &RectData,
+    metadata_policy: MetadataPolicy,
+) -> anyhow::Result<bool> {
+    let source = std::fs::read(img_path).context(format!("读取图片 {img_path:?} 失败"))?;
+    // 解码器能容忍的错误这里不一定能处理，重新编码失败时与不支持的类型一样处理
+    let Ok(Some(jpg_data)) = crate::jpeg::reencode_region(&source, page, rect) else {
+        return Ok(false);
+    };
+    let jpg_data = match metadata_policy {
+        MetadataPolicy::Preserve => jpg_data,
+        MetadataPolicy::Strip => crate::metadata::strip(&jpg_data).unwrap_or(jpg_data),
+    };
+    // 保证输出目录存在
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context(format!("创建目录 {parent:?} 失败"))?;
+    }
+    // 保证后缀为jpg
+    let path = path.with_extension("jpg");
+    std::fs::write(&path, jpg_data).context(format!("保存图片 {path:?} 失败"))?;
+    Ok(true)
+}
+
 /// 保存jpg图片`img`到指定路径`path`, `optimize`为true时会检查图片是否为灰度图像，如果是则保存为luma8图片
-#[allow(clippy::cast_possible_truncation)]
-fn save_jpg_image(img: &RgbImage, path: &Path, optimize: bool) -> anyhow::Result<()> {
-    let (width, height) = (img.width() as u16, img.height() as u16);
+fn save_jpg_image(
+    img: &RgbImage,
+    path: &Path,
+    optimize: bool,
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
+    let (width, height) = jpeg_dimensions(img.dimensions(), path)?;
     // 保证后缀为jpg
     let path = path.with_extension("jpg");
     // 保存去除水印后的图片，使用jpeg_encoder库的Encoder处理jpg效率更高
-    let encoder = jpeg_encoder::Encoder::new_file(&path, 95)?;
+    let mut encoder = jpeg_encoder::Encoder::new_file(&path, 95)?;
+    metadata.add_to_jpeg_encoder(&mut encoder)?;
     if optimize && is_grey_image(img) {
         let luma = image::DynamicImage::ImageRgb8(img.clone()).into_luma8();
-        encoder
-            .encode(luma.as_raw(), width, height, jpeg_encoder::ColorType::Luma)
-            .context(format!("编码luma8图片 {path:?} 失败"))?;
+        encode_luma_jpg(encoder, &luma, &path)?;
     } else {
         encoder
             .encode(img.as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
@@ -263,14 +916,52 @@ fn save_jpg_image(img: &RgbImage, path: &Path, optimize: bool) -> anyhow::Result
     Ok(())
 }
 
+/// 保存luma8的jpg图片`img`到指定路径`path`
+fn save_luma_jpg_image(img: &GrayImage, path: &Path, metadata: &Metadata) -> anyhow::Result<()> {
+    // 保证后缀为jpg
+    let path = path.with_extension("jpg");
+    // 先检查尺寸，避免留下空文件
+    jpeg_dimensions(img.dimensions(), &path)?;
+    let mut encoder = jpeg_encoder::Encoder::new_file(&path, 95)?;
+    metadata.add_to_jpeg_encoder(&mut encoder)?;
+    encode_luma_jpg(encoder, img, &path)
+}
+
+/// 用`encoder`把luma8图片`img`编码为jpg，`path`仅用于错误信息
+fn encode_luma_jpg<W: jpeg_encoder::JfifWrite>(
+    encoder: jpeg_encoder::Encoder<W>,
+    img: &GrayImage,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let (width, height) = jpeg_dimensions(img.dimensions(), path)?;
+    encoder
+        .encode(img.as_raw(), width, height, jpeg_encoder::ColorType::Luma)
+        .context(format!("编码luma8图片 {path:?} 失败"))?;
+    Ok(())
+}
+
+/// 把图片的尺寸转换为JPEG使用的16位整数，超过JPEG的上限时返回错误，`path`仅用于错误信息
+fn jpeg_dimensions((width, height): (u32, u32), path: &Path) -> anyhow::Result<(u16, u16)> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(anyhow!(
+            "图片 {path:?} 的尺寸({width}x{height})超过了JPEG的上限({MAX_JPEG_DIMENSION})"
+        )),
+    }
+}
+
 /// 保存png图片`img`到指定路径`path`, `optimize`为true时会检查图片是否为灰度图像，如果是则保存为luma8图片
 #[allow(clippy::cast_possible_truncation)]
-fn save_png_image(img: &RgbImage, path: &Path, optimize: bool) -> anyhow::Result<()> {
+fn save_png_image(
+    img: &RgbImage,
+    path: &Path,
+    optimize: bool,
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
     // 保证后缀为png
     let path = path.with_extension("png");
-    let png_file = std::fs::File::create(&path)?;
-    let buffered_file_writer = BufWriter::new(png_file);
-    let encoder = PngEncoder::new(buffered_file_writer);
+    let mut png_data = vec![];
+    let encoder = PngEncoder::new(&mut png_data);
     if optimize && is_grey_image(img) {
         let luma = image::DynamicImage::ImageRgb8(img.clone()).into_luma8();
         luma.write_with_encoder(encoder)
@@ -279,6 +970,69 @@ fn save_png_image(img: &RgbImage, path: &Path, optimize: bool) -> anyhow::Result
         img.write_with_encoder(encoder)
             .context(format!("编码rgb图片 {path:?} 失败"))?;
     }
+    write_png_file(&path, png_data, metadata)
+}
+
+/// 把图片`page`与透明通道`alpha`合并后保存为png图片，`optimize`为true时会检查RGB图片是否为灰度图像，如果是则保存为luma8+alpha图片
+fn save_png_image_with_alpha(
+    page: &Page,
+    alpha: &GrayImage,
+    path: &Path,
+    optimize: bool,
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
+    // 保证输出目录存在
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context(format!("创建目录 {parent:?} 失败"))?;
+    }
+    let img = match page {
+        Page::Rgb(img, _) if optimize && is_grey_image(img) => {
+            let luma = image::DynamicImage::ImageRgb8(img.clone()).into_luma8();
+            with_alpha_luma(&luma, alpha)
+        }
+        Page::Rgb(img, _) => {
+            DynamicImage::ImageRgba8(RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+                let [r, g, b] = img.get_pixel(x, y).0;
+                Rgba([r, g, b, alpha.get_pixel(x, y)[0]])
+            }))
+        }
+        Page::Luma(img) => with_alpha_luma(img, alpha),
+    };
+    // 保证后缀为png
+    let path = path.with_extension("png");
+    let mut png_data = vec![];
+    let encoder = PngEncoder::new(&mut png_data);
+    img.write_with_encoder(encoder)
+        .context(format!("编码带透明通道的图片 {path:?} 失败"))?;
+    write_png_file(&path, png_data, metadata)
+}
+
+/// 把luma8图片`img`与透明通道`alpha`合并为luma8+alpha图片
+fn with_alpha_luma(img: &GrayImage, alpha: &GrayImage) -> DynamicImage {
+    DynamicImage::ImageLumaA8(GrayAlphaImage::from_fn(
+        img.width(),
+        img.height(),
+        |x, y| LumaA([img.get_pixel(x, y)[0], alpha.get_pixel(x, y)[0]]),
+    ))
+}
+
+/// 保存luma8的png图片`img`到指定路径`path`
+fn save_luma_png_image(img: &GrayImage, path: &Path, metadata: &Metadata) -> anyhow::Result<()> {
+    // 保证后缀为png
+    let path = path.with_extension("png");
+    let mut png_data = vec![];
+    let encoder = PngEncoder::new(&mut png_data);
+    img.write_with_encoder(encoder)
+        .context(format!("编码luma8图片 {path:?} 失败"))?;
+    write_png_file(&path, png_data, metadata)
+}
+
+/// 把元数据`metadata`插入编码好的png数据`png_data`后保存到`path`
+fn write_png_file(path: &Path, png_data: Vec<u8>, metadata: &Metadata) -> anyhow::Result<()> {
+    let png_data = metadata
+        .insert_into_png(png_data)
+        .context(format!("写入图片 {path:?} 的元数据失败"))?;
+    std::fs::write(path, png_data).context(format!("保存图片 {path:?} 失败"))?;
     Ok(())
 }
 
@@ -288,3 +1042,155 @@ fn is_grey_image(img: &RgbImage) -> bool {
         r == g && g == b
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::watermark::ChromaSubsampling;
+
+    use super::*;
+
+    #[test]
+    fn truncated_jpg_falls_back_to_normal_saving() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("truncated-jpg-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let img = RgbImage::from_pixel(32, 32, image::Rgb([200, 100, 50]));
+        let mut jpg_data = vec![];
+        jpeg_encoder::Encoder::new(&mut jpg_data, 90).encode(
+            img.as_raw(),
+            32,
+            32,
+            jpeg_encoder::ColorType::Rgb,
+        )?;
+        // 去掉EOI标记，image库仍然能解码，但无法保留原图的块
+        jpg_data.truncate(jpg_data.len() - 2);
+        let img_path = dir.join("in.jpg");
+        std::fs::write(&img_path, &jpg_data)?;
+        let out_path = dir.join("out").join("in.jpg");
+        let rect = RectData {
+            left: 0,
+            top: 0,
+            right: 7,
+            bottom: 7,
+        };
+
+        let page = Page::Rgb(img, ChromaSubsampling::NONE);
+        let saved =
+            save_jpg_preserving_blocks(&page, &img_path, &out_path, &rect, MetadataPolicy::Strip)?;
+        assert!(!saved);
+        assert!(!out_path.exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_jpg_is_split_by_height() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("oversized-jpg-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let height = MAX_JPEG_DIMENSION + 10;
+        let page = Page::Rgb(
+            RgbImage::from_pixel(2, height, image::Rgb([200, 100, 50])),
+            ChromaSubsampling::NONE,
+        );
+        assert!(!fits_in_jpeg(&page));
+        let path = dir.join("in.jpg");
+
+        save_oversized_image(
+            &page,
+            &path,
+            OversizedJpegPolicy::Split,
+            false,
+            &Metadata::default(),
+        )?;
+        // 按照高度平均切分为两段，第一段多出一行
+        assert!(!path.exists());
+        let first = image::image_dimensions(dir.join("in_01.jpg"))?;
+        let second = image::image_dimensions(dir.join("in_02.jpg"))?;
+        assert_eq!((first, second), ((2, 32773), (2, 32772)));
+        assert!(!dir.join("in_03.jpg").exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_jpg_falls_back_to_png() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("oversized-png-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let tall = Page::Rgb(
+            RgbImage::from_pixel(2, MAX_JPEG_DIMENSION + 10, image::Rgb([200, 100, 50])),
+            ChromaSubsampling::NONE,
+        );
+        let wide = Page::Rgb(
+            RgbImage::from_pixel(MAX_JPEG_DIMENSION + 10, 2, image::Rgb([200, 100, 50])),
+            ChromaSubsampling::NONE,
+        );
+        let metadata = Metadata::default();
+
+        // 策略为PNG时不切分
+        let tall_path = dir.join("tall.jpg");
+        save_oversized_image(
+            &tall,
+            &tall_path,
+            OversizedJpegPolicy::Png,
+            false,
+            &metadata,
+        )?;
+        let tall_dimensions = image::image_dimensions(dir.join("tall.png"))?;
+        assert_eq!(tall_dimensions, (2, MAX_JPEG_DIMENSION + 10));
+        assert!(!dir.join("tall_01.jpg").exists());
+        // 宽度超过上限时无法按照高度切分
+        let wide_path = dir.join("wide.jpg");
+        save_oversized_image(
+            &wide,
+            &wide_path,
+            OversizedJpegPolicy::Split,
+            false,
+            &metadata,
+        )?;
+        let wide_dimensions = image::image_dimensions(dir.join("wide.png"))?;
+        assert_eq!(wide_dimensions, (MAX_JPEG_DIMENSION + 10, 2));
+        assert!(!dir.join("wide_01.jpg").exists());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    fn alpha_channel_survives_png_output() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("alpha-png-{}", std::process::id()));
+        let alpha = GrayImage::from_fn(16, 8, |x, y| image::Luma([(x * 16 + y) as u8]));
+        let colored = Page::Rgb(
+            RgbImage::from_pixel(16, 8, image::Rgb([200, 100, 50])),
+            ChromaSubsampling::NONE,
+        );
+        let grey = Page::Rgb(
+            RgbImage::from_pixel(16, 8, image::Rgb([120; 3])),
+            ChromaSubsampling::NONE,
+        );
+        let metadata = Metadata::default();
+
+        // 彩色图片保存为RGBA，后缀改为png
+        let colored_path = dir.join("colored.jpg");
+        save_png_image_with_alpha(&colored, &alpha, &colored_path, true, &metadata)?;
+        let colored_img = image::open(dir.join("colored.png"))?;
+        assert!(matches!(colored_img, DynamicImage::ImageRgba8(_)));
+        let (page, saved_alpha) = open_page(&dir.join("colored.png"))?;
+        assert_eq!(page.as_raw(), colored.as_raw());
+        assert_eq!(saved_alpha, Some(alpha.clone()));
+        // 开启优化时灰色图片保存为luma8+alpha
+        let grey_path = dir.join("grey.png");
+        save_png_image_with_alpha(&grey, &alpha, &grey_path, true, &metadata)?;
+        let grey_img = image::open(&grey_path)?;
+        assert!(matches!(grey_img, DynamicImage::ImageLumaA8(_)));
+        let (page, saved_alpha) = open_page(&grey_path)?;
+        assert!(page.is_luma());
+        assert_eq!(saved_alpha, Some(alpha));
+        // 完全不透明的图片打开时没有透明通道
+        let opaque_path = dir.join("opaque.png");
+        let opaque = GrayImage::from_pixel(16, 8, image::Luma([u8::MAX]));
+        save_png_image_with_alpha(&colored, &opaque, &opaque_path, true, &metadata)?;
+        let (_, saved_alpha) = open_page(&opaque_path)?;
+        assert!(saved_alpha.is_none());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
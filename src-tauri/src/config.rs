@@ -4,7 +4,9 @@ use serde::{Deserialize, Serialize};
 use specta::Type;
 use tauri::{AppHandle, Manager};
 
-use crate::types::ImageFormat;
+use crate::types::{
+    BackgroundThresholds, ImageFormat, InversionMode, MetadataPolicy, OversizedJpegPolicy,
+};
 
 #[allow(clippy::struct_field_names)]
 #[derive(Debug, Clone, Serialize, Deserialize, Type)]
@@ -13,6 +15,40 @@ pub struct Config {
     pub output_dir: PathBuf,
     pub output_format: ImageFormat,
     pub output_optimize: bool,
+    /// 以JPEG输出时是否只重新编码与水印相交的块，其他块保留原图的DCT系数
+    #[serde(default)]
+    pub preserve_jpeg_blocks: bool,
+    /// 以JPEG输出的图片尺寸超过JPEG的上限时的处理方式
+    #[serde(default)]
+    pub oversized_jpeg_policy: OversizedJpegPolicy,
+    /// 输出图片的元数据的处理方式
+    #[serde(default)]
+    pub metadata_policy: MetadataPolicy,
+    /// 水印任意一个通道的alpha小于这个值的像素用周围的像素修补，为0(默认)时不修补
+    #[serde(default)]
+    pub inpaint_alpha_threshold: f32,
+    /// 反推原图时使用的颜色空间
+    #[serde(default)]
+    pub inversion_mode: InversionMode,
+    /// 对齐水印时在水印原本的位置附近搜索的最大平移量，为0时不搜索
+    #[serde(default = "default_alignment_search_radius")]
+    pub alignment_search_radius: u32,
+    /// 是否在整数平移量的基础上进一步进行亚像素对齐
+    #[serde(default)]
+    pub subpixel_alignment: bool,
+    /// 是否对去过水印的区域去块和降噪，减轻被放大的JPEG块效应和噪点
+    #[serde(default)]
+    pub deblocking: bool,
+    /// 是否给没有对应尺寸背景水印图的图片借用其他尺寸的去水印模型，关闭(默认)时这些图片直接复制
+    #[serde(default)]
+    pub transplant_models: bool,
+    /// 判断图片是否满足背景条件时使用的阈值
+    #[serde(default)]
+    pub background_thresholds: BackgroundThresholds,
+}
+
+fn default_alignment_search_radius() -> u32 {
+    2
 }
 
 impl Config {
@@ -23,6 +59,16 @@ impl Config {
             output_dir: resource_dir,
             output_format: ImageFormat::Jpeg,
             output_optimize: false,
+            preserve_jpeg_blocks: false,
+            oversized_jpeg_policy: OversizedJpegPolicy::Png,
+            metadata_policy: MetadataPolicy::Preserve,
+            inpaint_alpha_threshold: 0.0,
+            inversion_mode: InversionMode::Rgb,
+            alignment_search_radius: default_alignment_search_radius(),
+            subpixel_alignment: false,
+            deblocking: false,
+            transplant_models: false,
+            background_thresholds: BackgroundThresholds::default(),
         };
         let config = if config_path.exists() {
             let config_string = std::fs::read_to_string(config_path)?;
@@ -4,8 +4,11 @@ use serde::{Deserialize, Serialize};
 use specta::Type;
 use tauri_specta::Event;
 
+use crate::types::RemovalQuality;
+
 pub mod prelude {
     pub use crate::events::{
+        GenerateBackgroundEndEvent, GenerateBackgroundProgressEvent, GenerateBackgroundStartEvent,
         RemoveWatermarkEndEvent, RemoveWatermarkErrorEvent, RemoveWatermarkStartEvent,
         RemoveWatermarkSuccessEvent,
     };
@@ -26,6 +29,8 @@ pub struct RemoveWatermarkSuccessEventPayload {
     pub dir_path: PathBuf,
     pub img_path: PathBuf,
     pub current: u32,
+    /// 去水印的质量，图片被原样复制时为`None`
+    pub quality: Option<RemovalQuality>,
 }
 #[derive(Serialize, Deserialize, Clone, Type, Event)]
 pub struct RemoveWatermarkSuccessEvent(pub RemoveWatermarkSuccessEventPayload);
@@ -47,3 +52,36 @@ pub struct RemoveWatermarkEndEventPayload {
 }
 #[derive(Serialize, Deserialize, Clone, Type, Event)]
 pub struct RemoveWatermarkEndEvent(pub RemoveWatermarkEndEventPayload);
+
+#[derive(Serialize, Deserialize, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateBackgroundStartEventPayload {
+    pub width: u32,
+    pub height: u32,
+    /// 需要扫描的图片数量
+    pub total: u32,
+}
+#[derive(Serialize, Deserialize, Clone, Type, Event)]
+pub struct GenerateBackgroundStartEvent(pub GenerateBackgroundStartEventPayload);
+
+#[derive(Serialize, Deserialize, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateBackgroundProgressEventPayload {
+    pub width: u32,
+    pub height: u32,
+    /// 已扫描的图片数量
+    pub current: u32,
+    /// 已找到的满足背景条件的图片数量
+    pub candidates: u32,
+}
+#[derive(Serialize, Deserialize, Clone, Type, Event)]
+pub struct GenerateBackgroundProgressEvent(pub GenerateBackgroundProgressEventPayload);
+
+#[derive(Serialize, Deserialize, Clone, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateBackgroundEndEventPayload {
+    pub width: u32,
+    pub height: u32,
+}
+#[derive(Serialize, Deserialize, Clone, Type, Event)]
+pub struct GenerateBackgroundEndEvent(pub GenerateBackgroundEndEventPayload);
@@ -0,0 +1,125 @@
+use anyhow::anyhow;
+
+/// 按位读取JPEG的熵编码数据，自动跳过填充在0xFF后的0x00
+pub(super) struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// 读取1位，遇到标记或者数据结束时补0
+    pub(super) fn read_bit(&mut self) -> u32 {
+        if self.count == 0 {
+            self.buffer = u32::from(self.next_byte());
+            self.count = 8;
+        }
+        self.count -= 1;
+        (self.buffer >> self.count) & 1
+    }
+
+    /// 读取`n`位，高位在前
+    pub(super) fn read_bits(&mut self, n: u8) -> u32 {
+        (0..n).fold(0, |bits, _| (bits << 1) | self.read_bit())
+    }
+
+    /// 丢弃剩余的位并跳过重置标记RSTn，重置间隔结束时调用
+    pub(super) fn restart(&mut self) -> anyhow::Result<()> {
+        self.count = 0;
+        // 标记前可能有多个用于填充的0xFF
+        while self.data.get(self.pos) == Some(&0xFF) && self.data.get(self.pos + 1) == Some(&0xFF) {
+            self.pos += 1;
+        }
+        match self.data.get(self.pos..self.pos + 2) {
+            Some([0xFF, marker]) if (0xD0..=0xD7).contains(marker) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err(anyhow!("熵编码数据的第{}个字节处缺少重置标记", self.pos)),
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        match self.data.get(self.pos..self.pos + 2) {
+            // 0xFF后的0x00是填充，不属于数据
+            Some([0xFF, 0x00]) => {
+                self.pos += 2;
+                0xFF
+            }
+            // 遇到标记时不前进，之后的读取都补0
+            Some([0xFF, _]) => 0,
+            _ => match self.data.get(self.pos) {
+                Some(&byte) => {
+                    self.pos += 1;
+                    byte
+                }
+                None => 0,
+            },
+        }
+    }
+}
+
+/// 按位写入JPEG的熵编码数据，自动在0xFF后填充0x00
+pub(super) struct BitWriter {
+    data: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    pub(super) fn new() -> Self {
+        Self {
+            data: vec![],
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    /// 写入`bits`的低`n`位，高位在前，`n`不超过16
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn write_bits(&mut self, bits: u32, n: u8) {
+        let n = u32::from(n);
+        self.buffer = (self.buffer << n) | (bits & ((1 << n) - 1));
+        self.count += n;
+        while self.count >= 8 {
+            self.count -= 8;
+            let byte = (self.buffer >> self.count) as u8;
+            self.data.push(byte);
+            if byte == 0xFF {
+                self.data.push(0x00);
+            }
+        }
+        self.buffer &= (1 << self.count) - 1;
+    }
+
+    /// 用1补齐最后一个字节
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn flush(&mut self) {
+        if self.count > 0 {
+            let padding = 8 - self.count;
+            self.write_bits((1 << padding) - 1, padding as u8);
+        }
+    }
+
+    /// 补齐最后一个字节后写入第`index % 8`个重置标记
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn write_restart(&mut self, index: usize) {
+        self.flush();
+        self.data.extend([0xFF, 0xD0 + (index % 8) as u8]);
+    }
+
+    pub(super) fn into_bytes(mut self) -> Vec<u8> {
+        self.flush();
+        self.data
+    }
+}
@@ -0,0 +1,57 @@
+use std::f32::consts::PI;
+use std::sync::OnceLock;
+
+/// zigzag顺序中第k个系数在8x8块中按行排列的位置
+pub(super) const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// 量化后DC系数的范围，保证相邻块的差值不超过11位
+const DC_RANGE: (i16, i16) = (-1024, 1023);
+/// 量化后AC系数的范围，基线JPEG的AC系数最多10位
+const AC_RANGE: (i16, i16) = (-1023, 1023);
+
+/// 对按行排列的8x8个采样值`samples`做DCT，用按照zigzag顺序排列的量化表`quant_table`量化，返回按照zigzag顺序排列的系数
+#[allow(clippy::cast_possible_truncation)]
+pub(super) fn forward_dct(samples: &[f32; 64], quant_table: &[u16; 64]) -> [i16; 64] {
+    let basis = basis();
+    // 先对每行做一维DCT，再对每列做一维DCT
+    let mut rows = [0.0_f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            rows[y * 8 + u] = (0..8).map(|x| basis[u][x] * samples[y * 8 + x]).sum();
+        }
+    }
+    let mut coefficients = [0.0_f32; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            coefficients[v * 8 + u] = (0..8).map(|y| basis[v][y] * rows[y * 8 + u]).sum();
+        }
+    }
+
+    let mut quantized = [0; 64];
+    for (k, value) in quantized.iter_mut().enumerate() {
+        let (min, max) = if k == 0 { DC_RANGE } else { AC_RANGE };
+        let coefficient = coefficients[ZIGZAG[k]] / f32::from(quant_table[k]);
+        *value = (coefficient.round() as i16).clamp(min, max);
+    }
+    quantized
+}
+
+/// 一维DCT的基函数，`basis[u][x] = C(u) / 2 * cos((2x + 1)uπ / 16)`，`C(0) = 1 / √2`，其余为1
+#[allow(clippy::cast_precision_loss)]
+fn basis() -> &'static [[f32; 8]; 8] {
+    static BASIS: OnceLock<[[f32; 8]; 8]> = OnceLock::new();
+    BASIS.get_or_init(|| {
+        let mut basis = [[0.0; 8]; 8];
+        for (u, row) in basis.iter_mut().enumerate() {
+            let scale = if u == 0 { 0.5 / 2.0_f32.sqrt() } else { 0.5 };
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.0).cos();
+            }
+        }
+        basis
+    })
+}
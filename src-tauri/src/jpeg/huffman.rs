@@ -0,0 +1,229 @@
+use anyhow::anyhow;
+
+use crate::jpeg::bits::BitReader;
+
+/// 霍夫曼表，与DHT段中的格式一致
+#[derive(Clone)]
+pub(super) struct HuffmanTable {
+    /// 码长为1到16的码字的数量
+    pub(super) counts: [u8; 16],
+    /// 按照码长从短到长排列的符号
+    pub(super) values: Vec<u8>,
+}
+
+/// 用于解码的霍夫曼表，按照码长保存规范霍夫曼编码的范围
+pub(super) struct HuffmanDecoder {
+    /// 每种码长的最小码字
+    min_codes: [u32; 16],
+    /// 每种码长的最大码字，没有这种码长时为-1
+    max_codes: [i32; 16],
+    /// 每种码长的第一个符号在`values`中的位置
+    value_offsets: [usize; 16],
+    values: Vec<u8>,
+}
+
+/// 用于编码的霍夫曼表，`codes[symbol]`为符号的(码字, 码长)
+pub(super) struct HuffmanEncoder {
+    codes: [(u16, u8); 256],
+}
+
+impl HuffmanTable {
+    #[allow(clippy::cast_possible_wrap)]
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn decoder(&self) -> HuffmanDecoder {
+        let mut min_codes = [0; 16];
+        let mut max_codes = [-1; 16];
+        let mut value_offsets = [0; 16];
+        let mut code = 0;
+        let mut offset = 0;
+        for (len, &count) in self.counts.iter().enumerate() {
+            if count > 0 {
+                min_codes[len] = code;
+                value_offsets[len] = offset;
+                code += u32::from(count);
+                offset += usize::from(count);
+                max_codes[len] = code as i32 - 1;
+            }
+            code <<= 1;
+        }
+        HuffmanDecoder {
+            min_codes,
+            max_codes,
+            value_offsets,
+            values: self.values.clone(),
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn encoder(&self) -> HuffmanEncoder {
+        let mut codes = [(0, 0); 256];
+        let mut code = 0_u16;
+        let mut values = self.values.iter();
+        for (len, &count) in self.counts.iter().enumerate() {
+            for _ in 0..count {
+                if let Some(&value) = values.next() {
+                    codes[usize::from(value)] = (code, len as u8 + 1);
+                }
+                code += 1;
+            }
+            code <<= 1;
+        }
+        HuffmanEncoder { codes }
+    }
+
+    /// 根据每个符号出现的次数`frequencies`生成最优的霍夫曼表，码长不超过16
+    ///
+    /// 与libjpeg的`jpeg_gen_optimal_table`相同，额外保留一个出现1次的符号，保证不会出现全为1的码字
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn optimal(frequencies: &[u32; 256]) -> Self {
+        const RESERVED: usize = 256;
+        let mut frequencies: Vec<u64> = frequencies.iter().map(|&f| u64::from(f)).collect();
+        frequencies.push(1);
+        let mut code_sizes = [0_usize; 257];
+        // 同一棵子树中的符号用链表串起来，合并子树时整条链上的码长都加1
+        let mut others: [Option<usize>; 257] = [None; 257];
+        loop {
+            // 出现次数最少的两个符号，次数相同时取序号大的
+            let smallest = |exclude: Option<usize>| {
+                (0..=RESERVED)
+                    .filter(|&i| frequencies[i] > 0 && Some(i) != exclude)
+                    .min_by(|&a, &b| frequencies[a].cmp(&frequencies[b]).then(b.cmp(&a)))
+            };
+            let Some(c1) = smallest(None) else {
+                break;
+            };
+            let Some(c2) = smallest(Some(c1)) else {
+                break;
+            };
+            frequencies[c1] += frequencies[c2];
+            frequencies[c2] = 0;
+            let mut c = c1;
+            code_sizes[c] += 1;
+            while let Some(next) = others[c] {
+                c = next;
+                code_sizes[c] += 1;
+            }
+            others[c] = Some(c2);
+            let mut c = c2;
+            code_sizes[c] += 1;
+            while let Some(next) = others[c] {
+                c = next;
+                code_sizes[c] += 1;
+            }
+        }
+
+        let max_size = code_sizes.iter().copied().max().unwrap_or(0).max(16);
+        let mut bits = vec![0_usize; max_size + 1];
+        for &size in code_sizes.iter().filter(|&&size| size > 0) {
+            bits[size] += 1;
+        }
+        // 把超过16的码长调整到16以内，每次把最长的两个码字换成一个短一位的码字和两个更短的码字
+        for i in (17..=max_size).rev() {
+            while bits[i] > 0 {
+                let mut j = i - 2;
+                while bits[j] == 0 {
+                    j -= 1;
+                }
+                bits[i] -= 2;
+                bits[i - 1] += 1;
+                bits[j + 1] += 2;
+                bits[j] -= 1;
+            }
+        }
+        // 去掉保留的符号，它的码长最长
+        if let Some(longest) = (1..=16).rev().find(|&i| bits[i] > 0) {
+            bits[longest] -= 1;
+        }
+
+        let mut counts = [0; 16];
+        for (count, &bit) in counts.iter_mut().zip(&bits[1..=16]) {
+            // 符号最多256个，每种码长的数量不会超过u8的范围
+            *count = bit as u8;
+        }
+        let mut values = vec![];
+        for size in 1..=max_size {
+            let symbols = code_sizes[..RESERVED].iter().enumerate();
+            values.extend(
+                symbols
+                    .filter(|(_, &s)| s == size)
+                    .map(|(symbol, _)| symbol as u8),
+            );
+        }
+        Self { counts, values }
+    }
+
+    /// 序列化为DHT段中一张表的数据，`class`为0表示DC表，为1表示AC表
+    pub(super) fn write_to(&self, class: u8, id: u8, out: &mut Vec<u8>) {
+        out.push((class << 4) | id);
+        out.extend(self.counts);
+        out.extend(&self.values);
+    }
+}
+
+impl HuffmanDecoder {
+    #[allow(clippy::cast_possible_wrap)]
+    pub(super) fn decode(&self, reader: &mut BitReader) -> anyhow::Result<u8> {
+        let mut code = 0;
+        for len in 0..16 {
+            code = (code << 1) | reader.read_bit();
+            if self.max_codes[len] >= code as i32 {
+                let index = self.value_offsets[len] + (code - self.min_codes[len]) as usize;
+                return self
+                    .values
+                    .get(index)
+                    .copied()
+                    .ok_or(anyhow!("霍夫曼表中缺少第{index}个符号"));
+            }
+        }
+        Err(anyhow!("熵编码数据中有无效的霍夫曼码字"))
+    }
+}
+
+impl HuffmanEncoder {
+    /// 符号的(码字, 码长)
+    pub(super) fn code(&self, symbol: u8) -> (u16, u8) {
+        self.codes[usize::from(symbol)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jpeg::bits::BitWriter;
+
+    use super::*;
+
+    #[test]
+    fn optimal_limits_code_length() -> anyhow::Result<()> {
+        // 出现次数为斐波那契数列时，不限制码长的霍夫曼编码中最长的码字有将近40位
+        let mut frequencies = [0_u32; 256];
+        let (mut a, mut b) = (1, 1);
+        for frequency in frequencies.iter_mut().take(40) {
+            *frequency = a;
+            (a, b) = (b, a + b);
+        }
+        let table = HuffmanTable::optimal(&frequencies);
+        let counts = table.counts.iter().map(|&count| usize::from(count));
+        assert_eq!(counts.sum::<usize>(), 40);
+        assert_eq!(table.values.len(), 40);
+        // 码长不超过16，而且Kraft不等式严格成立，说明没有全为1的码字
+        let kraft: u32 = (0..16)
+            .map(|len| u32::from(table.counts[len]) << (15 - len))
+            .sum();
+        assert!(kraft < 1 << 16);
+
+        // 编码后再解码，得到原本的符号
+        let encoder = table.encoder();
+        let decoder = table.decoder();
+        let mut writer = BitWriter::new();
+        for symbol in 0..40 {
+            let (code, len) = encoder.code(symbol);
+            writer.write_bits(u32::from(code), len);
+        }
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        for symbol in 0..40 {
+            assert_eq!(decoder.decode(&mut reader)?, symbol);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+use crate::jpeg::parse::JpegFile;
+use crate::types::RectData;
+use crate::watermark::{ChromaSubsampling, Page};
+
+mod bits;
+mod dct;
+mod huffman;
+mod parse;
+mod scan;
+
+/// 只重新编码原图中与`rect`相交的MCU，其他MCU保留原本量化后的DCT系数，返回新的JPEG文件
+///
+/// `source`是原图的JPEG文件，`page`是`source`解码后去过水印的图片，只有`rect`内的像素被修改过
+/// 重新编码的MCU使用原图的量化表，所以`rect`以外的像素与原图完全相同，而且不会因为量化表变大而变大
+/// 所有块重新统计后生成最优的霍夫曼表，文件通常比原图还小
+/// `source`不是JPEG、JPEG的类型不支持或者与`page`的尺寸、通道数量不一致时返回`None`
+pub fn reencode_region(
+    source: &[u8],
+    page: &Page,
+    rect: &RectData,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(mut file) = parse::parse(source)? else {
+        return Ok(None);
+    };
+    let (width, height) = page.dimensions();
+    let dimensions_match = (file.width, file.height) == (width as usize, height as usize);
+    if !dimensions_match || file.components.len() != page.channels() {
+        return Ok(None);
+    }
+    scan::decode_scan(&mut file)?;
+    reencode_mcus(&mut file, page, rect);
+    Ok(Some(write_file(&file)))
+}
+
+/// 原图`source`的色度采样块，`source`不是YCbCr的JPEG或者Cb、Cr的采样因子不一致时返回`None`
+///
+/// 只读取SOF段，所以渐进式等不支持重新编码的JPEG也能得到采样块
+pub fn chroma_subsampling(source: &[u8]) -> Option<ChromaSubsampling> {
+    let factors = parse::sampling_factors(source)?;
+    let [(luma_h, luma_v), cb, cr] = factors[..] else {
+        return None;
+    };
+    let (chroma_h, chroma_v) = cb;
+    if cb != cr || luma_h % chroma_h != 0 || luma_v % chroma_v != 0 {
+        return None;
+    }
+    Some(ChromaSubsampling {
+        width: u32::try_from(luma_h / chroma_h).ok()?,
+        height: u32::try_from(luma_v / chroma_v).ok()?,
+    })
+}
+
+/// 用`page`中的像素重新计算与`rect`相交的所有MCU中每个块的系数
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_precision_loss)]
+fn reencode_mcus(file: &mut JpegFile, page: &Page, rect: &RectData) {
+    let (mcu_width, mcu_height) = (8 * file.max_h, 8 * file.max_v);
+    let channels = page.channels();
+    let raw = page.as_raw();
+    // 返回(x, y)处像素第`component`个分量的值，超出图片范围时取最近的边缘像素，与编码器补齐的方式一致
+    let (width, height) = (file.width, file.height);
+    let component_at = |x: usize, y: usize, component: usize| -> f32 {
+        let (x, y) = (x.min(width - 1), y.min(height - 1));
+        let index = (y * width + x) * channels;
+        if channels == 1 {
+            return f32::from(raw[index]);
+        }
+        let rgb = [0, 1, 2].map(|i| f32::from(raw[index + i]));
+        rgb_to_ycbcr(rgb)[component]
+    };
+
+    let (max_h, max_v) = (file.max_h, file.max_v);
+    let mcu_xs = rect.left as usize / mcu_width..=rect.right as usize / mcu_width;
+    let mcu_ys = rect.top as usize / mcu_height..=rect.bottom as usize / mcu_height;
+    for mcu_y in mcu_ys {
+        for mcu_x in mcu_xs.clone() {
+            for (index, component) in file.components.iter_mut().enumerate() {
+                // 每个采样点覆盖(scale_x, scale_y)个像素，取平均值
+                let (scale_x, scale_y) = (max_h / component.h, max_v / component.v);
+                let sample_count = (scale_x * scale_y) as f32;
+                let Some(quant_table) = &file.quant_tables[component.quant_table] else {
+                    continue;
+                };
+                for block_y in 0..component.v {
+                    for block_x in 0..component.h {
+                        let x = mcu_x * component.h + block_x;
+                        let y = mcu_y * component.v + block_y;
+                        let mut samples = [0.0; 64];
+                        for (i, sample) in samples.iter_mut().enumerate() {
+                            let sample_x = (x * 8 + i % 8) * scale_x;
+                            let sample_y = (y * 8 + i / 8) * scale_y;
+                            let mut sum = 0.0;
+                            for dy in 0..scale_y {
+                                for dx in 0..scale_x {
+                                    sum += component_at(sample_x + dx, sample_y + dy, index);
+                                }
+                            }
+                            *sample = sum / sample_count - 128.0;
+                        }
+                        component.blocks[y * component.blocks_per_line + x] =
+                            dct::forward_dct(&samples, quant_table);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 按照原图的段的顺序写出新的JPEG文件，霍夫曼表和熵编码数据重新生成
+#[allow(clippy::cast_possible_truncation)]
+fn write_file(file: &JpegFile) -> Vec<u8> {
+    let (dc_tables, ac_tables, scan_data) = scan::encode_scan(file);
+    let mut out = Vec::with_capacity(file.scan_data.len() + 4096);
+    // SOI
+    out.extend([0xFF, 0xD8]);
+    for segment in &file.segments {
+        out.extend(*segment);
+    }
+    // DHT
+    let mut tables = vec![];
+    for (class, tables_of_class) in [(0, &dc_tables), (1, &ac_tables)] {
+        for (id, table) in tables_of_class.iter().enumerate() {
+            if let Some(table) = table {
+                table.write_to(class, id as u8, &mut tables);
+            }
+        }
+    }
+    out.extend([0xFF, 0xC4]);
+    out.extend(((tables.len() + 2) as u16).to_be_bytes());
+    out.extend(tables);
+    // SOS
+    out.extend(file.scan_header);
+    out.extend(scan_data);
+    // EOI
+    out.extend([0xFF, 0xD9]);
+    out
+}
+
+/// JFIF使用的BT.601全范围RGB到YCbCr的转换
+fn rgb_to_ycbcr([r, g, b]: [f32; 3]) -> [f32; 3] {
+    [
+        0.299 * r + 0.587 * g + 0.114 * b,
+        128.0 - 0.168_736 * r - 0.331_264 * g + 0.5 * b,
+        128.0 + 0.5 * r - 0.418_688 * g - 0.081_312 * b,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use image::{GrayImage, RgbImage};
+    use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+
+    use super::*;
+
+    /// 尺寸不是MCU的整数倍，而且每个块的内容都不同的测试图片
+    #[allow(clippy::cast_possible_truncation)]
+    fn test_image(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 7 + y * 3) as u8, (x * y) as u8, ((x ^ y) * 5) as u8])
+        })
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn encode(
+        raw: &[u8],
+        (width, height): (u32, u32),
+        color_type: ColorType,
+        sampling_factor: SamplingFactor,
+        restart_interval: u16,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![];
+        let mut encoder = Encoder::new(&mut data, 90);
+        encoder.set_sampling_factor(sampling_factor);
+        encoder.set_restart_interval(restart_interval);
+        encoder.encode(raw, width as u16, height as u16, color_type)?;
+        Ok(data)
+    }
+
+    /// 各种采样因子、重置间隔的彩色JPEG和灰度JPEG
+    fn test_jpegs() -> anyhow::Result<Vec<Vec<u8>>> {
+        let img = test_image(45, 29);
+        let gray = GrayImage::from_fn(45, 29, |x, y| image::Luma([img.get_pixel(x, y).0[0]]));
+        let mut jpegs = vec![];
+        for (sampling_factor, restart_interval) in [
+            (SamplingFactor::R_4_4_4, 0),
+            (SamplingFactor::R_4_2_2, 0),
+            (SamplingFactor::R_4_2_0, 0),
+            (SamplingFactor::R_4_2_0, 1),
+            (SamplingFactor::R_4_2_2, 3),
+        ] {
+            let raw = img.as_raw();
+            let dimensions = img.dimensions();
+            jpegs.push(encode(
+                raw,
+                dimensions,
+                ColorType::Rgb,
+                sampling_factor,
+                restart_interval,
+            )?);
+        }
+        for restart_interval in [0, 2] {
+            let raw = gray.as_raw();
+            let dimensions = gray.dimensions();
+            let sampling_factor = SamplingFactor::R_4_4_4;
+            jpegs.push(encode(
+                raw,
+                dimensions,
+                ColorType::Luma,
+                sampling_factor,
+                restart_interval,
+            )?);
+        }
+        Ok(jpegs)
+    }
+
+    fn decode(data: &[u8]) -> anyhow::Result<JpegFile<'_>> {
+        let mut file = parse::parse(data)?.context("不支持的JPEG")?;
+        scan::decode_scan(&mut file)?;
+        Ok(file)
+    }
+
+    fn coefficients(file: &JpegFile) -> Vec<Vec<[i16; 64]>> {
+        file.components.iter().map(|c| c.blocks.clone()).collect()
+    }
+
+    #[test]
+    fn rewrite_keeps_coefficients() -> anyhow::Result<()> {
+        for data in test_jpegs()? {
+            let file = decode(&data)?;
+            let rewritten = write_file(&file);
+            let rewritten_file = decode(&rewritten)?;
+            assert_eq!(coefficients(&rewritten_file), coefficients(&file));
+            assert_eq!(rewritten_file.restart_interval, file.restart_interval);
+            // 系数和量化表都相同，其他解码器解码出的像素也应该完全相同
+            let original = image::load_from_memory(&data)?;
+            let decoded = image::load_from_memory(&rewritten)?;
+            assert_eq!(decoded.as_bytes(), original.as_bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn restart_markers_are_rewritten() -> anyhow::Result<()> {
+        let img = test_image(45, 29);
+        let data = encode(
+            img.as_raw(),
+            img.dimensions(),
+            ColorType::Rgb,
+            SamplingFactor::R_4_2_0,
+            1,
+        )?;
+        let file = decode(&data)?;
+        let rewritten = write_file(&file);
+        let file = decode(&rewritten)?;
+        // 每个MCU之后都有一个重置标记，最后一个MCU除外
+        let scan_data = file.scan_data;
+        let restart_count = scan_data
+            .windows(2)
+            .filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1]))
+            .count();
+        assert_eq!(restart_count, file.mcus_per_line * file.mcus_per_column - 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reencode_region_keeps_blocks_outside_rect() -> anyhow::Result<()> {
+        let rect = RectData {
+            left: 20,
+            top: 18,
+            right: 35,
+            bottom: 24,
+        };
+        for data in test_jpegs()? {
+            let original = decode(&data)?;
+            let mut page = Page::from(image::load_from_memory(&data)?);
+            match &mut page {
+                Page::Rgb(img, _) => img.put_pixel(28, 21, image::Rgb([255, 255, 255])),
+                Page::Luma(img) => img.put_pixel(28, 21, image::Luma([255])),
+            }
+            let reencoded = reencode_region(&data, &page, &rect)?.context("不支持的JPEG")?;
+            let file = decode(&reencoded)?;
+
+            let (mcu_width, mcu_height) = (8 * file.max_h, 8 * file.max_v);
+            let mcu_xs = rect.left as usize / mcu_width..=rect.right as usize / mcu_width;
+            let mcu_ys = rect.top as usize / mcu_height..=rect.bottom as usize / mcu_height;
+            let mut changed = false;
+            for (component, original_component) in file.components.iter().zip(&original.components)
+            {
+                let blocks = component.blocks.iter().zip(&original_component.blocks);
+                for (index, (block, original_block)) in blocks.enumerate() {
+                    let x = index % component.blocks_per_line / component.h;
+                    let y = index / component.blocks_per_line / component.v;
+                    if mcu_xs.contains(&x) && mcu_ys.contains(&y) {
+                        changed |= block != original_block;
+                    } else {
+                        assert_eq!(block, original_block);
+                    }
+                }
+            }
+            assert!(changed);
+            // 重新编码的MCU应该接近修改后的像素
+            let decoded = image::load_from_memory(&reencoded)?.to_luma8();
+            assert!(decoded.get_pixel(28, 21).0[0] > 200);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn chroma_subsampling_follows_sampling_factors() -> anyhow::Result<()> {
+        let img = test_image(45, 29);
+        for (sampling_factor, expected) in [
+            (SamplingFactor::R_4_4_4, (1, 1)),
+            (SamplingFactor::R_4_2_2, (2, 1)),
+            (SamplingFactor::R_4_2_0, (2, 2)),
+            (SamplingFactor::R_4_1_1, (4, 1)),
+        ] {
+            let data = encode(
+                img.as_raw(),
+                img.dimensions(),
+                ColorType::Rgb,
+                sampling_factor,
+                0,
+            )?;
+            let subsampling = chroma_subsampling(&data).context("找不到色度采样块")?;
+            assert_eq!((subsampling.width, subsampling.height), expected);
+        }
+        let gray = GrayImage::new(45, 29);
+        let sampling_factor = SamplingFactor::R_4_4_4;
+        let data = encode(
+            gray.as_raw(),
+            gray.dimensions(),
+            ColorType::Luma,
+            sampling_factor,
+            0,
+        )?;
+        assert_eq!(chroma_subsampling(&data), None);
+        assert_eq!(chroma_subsampling(b"not a jpeg"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn broken_jpegs_are_errors() -> anyhow::Result<()> {
+        let img = test_image(45, 29);
+        let page = Page::Rgb(img.clone(), ChromaSubsampling::NONE);
+        let rect = RectData {
+            left: 0,
+            top: 0,
+            right: 7,
+            bottom: 7,
+        };
+        let encode_rgb = |restart_interval| {
+            let dimensions = img.dimensions();
+            let sampling_factor = SamplingFactor::R_4_2_0;
+            encode(
+                img.as_raw(),
+                dimensions,
+                ColorType::Rgb,
+                sampling_factor,
+                restart_interval,
+            )
+        };
+        let data = encode_rgb(0)?;
+        let position = |data: &[u8], marker: u8| {
+            data.windows(2)
+                .position(|w| w == [0xFF, marker])
+                .context("找不到标记")
+        };
+        let mut broken = vec![];
+        // 缺少EOI
+        broken.push(data[..data.len() - 2].to_vec());
+        // 熵编码数据被截断
+        broken.push(data[..data.len() / 2].to_vec());
+        // 缺少第一个重置标记
+        let mut missing_restart = encode_rgb(1)?;
+        let restart = position(&missing_restart, 0xD0)?;
+        missing_restart.drain(restart..restart + 2);
+        broken.push(missing_restart);
+        // 全为1的码字是无效的霍夫曼码字
+        let scan_start = position(&data, 0xDA)?;
+        let scan_length = usize::from(u16::from_be_bytes([
+            data[scan_start + 2],
+            data[scan_start + 3],
+        ]));
+        let mut invalid_code = data[..scan_start + 2 + scan_length].to_vec();
+        invalid_code.extend([0xFF, 0x00].repeat(64));
+        invalid_code.extend([0xFF, 0xD9]);
+        broken.push(invalid_code);
+        // 量化表中有为0的值
+        let mut zero_quant = data.clone();
+        let dqt = position(&zero_quant, 0xDB)?;
+        zero_quant[dqt + 5] = 0;
+        broken.push(zero_quant);
+        // SOS段出现在SOF段之前
+        broken.push(vec![
+            0xFF, 0xD8, 0xFF, 0xDA, 0x00, 0x08, 0x01, 0x01, 0x00, 0x00, 0x3F, 0x00, 0xFF, 0xD9,
+        ]);
+
+        for data in broken {
+            assert!(reencode_region(&data, &page, &rect).is_err());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,411 @@
+use anyhow::{anyhow, Context};
+
+use crate::jpeg::huffman::HuffmanTable;
+
+/// 帧中的一个颜色分量
+pub(super) struct Component {
+    pub(super) id: u8,
+    /// 水平采样因子
+    pub(super) h: usize,
+    /// 垂直采样因子
+    pub(super) v: usize,
+    pub(super) quant_table: usize,
+    pub(super) dc_table: usize,
+    pub(super) ac_table: usize,
+    /// 每行的块数，按照MCU补齐
+    pub(super) blocks_per_line: usize,
+    /// 每个块量化后的DCT系数，按照zigzag顺序排列，块按行排列
+    pub(super) blocks: Vec<[i16; 64]>,
+}
+
+/// 解析后的JPEG文件，只支持8位精度、霍夫曼编码、所有分量在同一次扫描中的顺序JPEG
+pub(super) struct JpegFile<'a> {
+    pub(super) width: usize,
+    pub(super) height: usize,
+    pub(super) components: Vec<Component>,
+    /// 所有分量中最大的水平采样因子
+    pub(super) max_h: usize,
+    /// 所有分量中最大的垂直采样因子
+    pub(super) max_v: usize,
+    pub(super) mcus_per_line: usize,
+    pub(super) mcus_per_column: usize,
+    /// 量化表，按照zigzag顺序排列
+    pub(super) quant_tables: [Option<[u16; 64]>; 4],
+    pub(super) dc_tables: [Option<HuffmanTable>; 4],
+    pub(super) ac_tables: [Option<HuffmanTable>; 4],
+    /// 每隔多少个MCU插入一个重置标记，为0时不插入
+    pub(super) restart_interval: usize,
+    /// 除了SOI、DHT、SOS和EOI以外的所有段，包括标记，按照原本的顺序排列
+    pub(super) segments: Vec<&'a [u8]>,
+    /// SOS段，包括标记
+    pub(super) scan_header: &'a [u8],
+    /// 熵编码数据
+    pub(super) scan_data: &'a [u8],
+}
+
+/// 解析JPEG文件`data`，不是JPEG或者不支持这种JPEG时返回`None`
+///
+/// 不支持渐进式、算术编码、无损、12位精度的JPEG，也不支持分多次扫描的JPEG，这些JPEG在漫画中很少见
+/// 只支持1个分量的灰度JPEG和3个分量的YCbCr JPEG
+pub(super) fn parse(data: &[u8]) -> anyhow::Result<Option<JpegFile<'_>>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Ok(None);
+    }
+    let mut headers = Headers::default();
+    let mut pos = 2;
+    loop {
+        let marker = next_marker(data, &mut pos)?;
+        match marker {
+            // EOI
+            0xD9 => break,
+            // 没有长度的独立标记
+            0x01 | 0xD0..=0xD7 => continue,
+            _ => {}
+        }
+        // 扫描之后只允许EOI，有其他段说明分了多次扫描
+        if headers.scan.is_some() {
+            return Ok(None);
+        }
+        let length = data
+            .get(pos..pos + 2)
+            .map(|bytes| usize::from(u16::from_be_bytes([bytes[0], bytes[1]])))
+            .filter(|&length| length >= 2)
+            .ok_or(anyhow!("第{pos}个字节处的段长度无效"))?;
+        let segment = data
+            .get(pos - 2..pos + length)
+            .ok_or(anyhow!("第{pos}个字节处的段超出了文件范围"))?;
+        pos += length;
+        if !headers.read_segment(marker, segment, data, &mut pos)? {
+            return Ok(None);
+        }
+    }
+    headers.into_jpeg_file()
+}
+
+/// 解析JPEG文件时从各个段中读到的数据
+#[derive(Default)]
+struct Headers<'a> {
+    /// SOF段中的(宽度, 高度, 所有分量)
+    frame: Option<(usize, usize, Vec<Component>)>,
+    quant_tables: [Option<[u16; 64]>; 4],
+    dc_tables: [Option<HuffmanTable>; 4],
+    ac_tables: [Option<HuffmanTable>; 4],
+    restart_interval: usize,
+    /// 除了SOI、DHT、SOS和EOI以外的所有段
+    segments: Vec<&'a [u8]>,
+    /// (SOS段, 熵编码数据)
+    scan: Option<(&'a [u8], &'a [u8])>,
+    /// APP14中的颜色变换为0时，3个分量是RGB而不是YCbCr
+    adobe_rgb: bool,
+}
+
+impl<'a> Headers<'a> {
+    /// 读取标记为`marker`的段`segment`(包括标记)，不支持这个段时返回false
+    ///
+    /// SOS段之后紧跟着熵编码数据，所以读取SOS段时会把`pos`移动到文件`data`中熵编码数据之后
+    fn read_segment(
+        &mut self,
+        marker: u8,
+        segment: &'a [u8],
+        data: &'a [u8],
+        pos: &mut usize,
+    ) -> anyhow::Result<bool> {
+        let payload = &segment[4..];
+        match marker {
+            // 基线、扩展顺序JPEG
+            0xC0 | 0xC1 => {
+                self.frame = parse_frame(payload)?;
+                if self.frame.is_none() {
+                    return Ok(false);
+                }
+            }
+            // 其他SOF，渐进式、无损、算术编码等
+            0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return Ok(false),
+            // DHT，最后会重新生成，所以不保留
+            0xC4 => {
+                parse_huffman_tables(payload, &mut self.dc_tables, &mut self.ac_tables)?;
+                return Ok(true);
+            }
+            // DQT
+            0xDB => parse_quant_tables(payload, &mut self.quant_tables)?,
+            // DRI
+            0xDD => {
+                let bytes = payload.get(..2).ok_or(anyhow!("DRI段的长度无效"))?;
+                self.restart_interval = usize::from(u16::from_be_bytes([bytes[0], bytes[1]]));
+            }
+            // SOS，不保留
+            0xDA => return self.read_scan(segment, data, pos),
+            // APP14
+            0xEE => self.adobe_rgb = payload.starts_with(b"Adobe") && payload.get(11) == Some(&0),
+            _ => {}
+        }
+        self.segments.push(segment);
+        Ok(true)
+    }
+
+    /// 读取SOS段`segment`和紧跟在`pos`之后的熵编码数据，不支持这次扫描时返回false
+    fn read_scan(
+        &mut self,
+        segment: &'a [u8],
+        data: &'a [u8],
+        pos: &mut usize,
+    ) -> anyhow::Result<bool> {
+        let Some((_, _, components)) = &mut self.frame else {
+            return Err(anyhow!("SOS段出现在SOF段之前"));
+        };
+        if !parse_scan(&segment[4..], components)? {
+            return Ok(false);
+        }
+        let end = scan_data_end(data, *pos);
+        self.scan = Some((segment, &data[*pos..end]));
+        *pos = end;
+        Ok(true)
+    }
+
+    /// 检查读到的数据是否完整，并按照MCU的排列方式为每个分量分配系数的空间
+    fn into_jpeg_file(self) -> anyhow::Result<Option<JpegFile<'a>>> {
+        let (Some((width, height, mut components)), Some((scan_header, scan_data))) =
+            (self.frame, self.scan)
+        else {
+            return Err(anyhow!("缺少SOF段或SOS段"));
+        };
+        if self.adobe_rgb && components.len() == 3 {
+            return Ok(None);
+        }
+        // 只有1个分量时不交错，每个MCU只有1个块
+        if components.len() == 1 {
+            components[0].h = 1;
+            components[0].v = 1;
+        }
+        let max_h = components.iter().map(|c| c.h).max().unwrap_or(1);
+        let max_v = components.iter().map(|c| c.v).max().unwrap_or(1);
+        let mcus_per_line = width.div_ceil(8 * max_h);
+        let mcus_per_column = height.div_ceil(8 * max_v);
+        for component in &mut components {
+            if self.quant_tables[component.quant_table].is_none()
+                || self.dc_tables[component.dc_table].is_none()
+                || self.ac_tables[component.ac_table].is_none()
+            {
+                return Err(anyhow!("分量{}使用的量化表或霍夫曼表不存在", component.id));
+            }
+            component.blocks_per_line = mcus_per_line * component.h;
+            let block_count = component.blocks_per_line * mcus_per_column * component.v;
+            component.blocks = vec![[0; 64]; block_count];
+        }
+
+        Ok(Some(JpegFile {
+            width,
+            height,
+            components,
+            max_h,
+            max_v,
+            mcus_per_line,
+            mcus_per_column,
+            quant_tables: self.quant_tables,
+            dc_tables: self.dc_tables,
+            ac_tables: self.ac_tables,
+            restart_interval: self.restart_interval,
+            segments: self.segments,
+            scan_header,
+            scan_data,
+        }))
+    }
+}
+
+/// 跳过填充的0xFF，返回下一个标记，`pos`指向标记之后
+fn next_marker(data: &[u8], pos: &mut usize) -> anyhow::Result<u8> {
+    if data.get(*pos) != Some(&0xFF) {
+        return Err(anyhow!("第{}个字节处缺少标记", *pos));
+    }
+    while data.get(*pos) == Some(&0xFF) {
+        *pos += 1;
+    }
+    let marker = *data.get(*pos).context("文件在标记处意外结束")?;
+    *pos += 1;
+    Ok(marker)
+}
+
+/// 读取JPEG文件`data`中SOF段里每个分量的采样因子(h, v)，支持所有类型的SOF段，找不到SOF段或者采样因子无效时返回`None`
+pub(super) fn sampling_factors(data: &[u8]) -> Option<Vec<(usize, usize)>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    loop {
+        match next_marker(data, &mut pos).ok()? {
+            // SOF段一定在SOS段之前
+            0xD9 | 0xDA => return None,
+            // 没有长度的独立标记
+            0x01 | 0xD0..=0xD7 => {}
+            // 除了DHT、JPG、DAC以外的0xC0到0xCF都是SOF
+            marker @ 0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let payload = data.get(pos + 2..)?;
+                let component_count = usize::from(*payload.get(5)?);
+                return (0..component_count)
+                    .map(|i| {
+                        let factors = *payload.get(7 + i * 3)?;
+                        let (h, v) = (usize::from(factors >> 4), usize::from(factors & 0x0F));
+                        ((1..=4).contains(&h) && (1..=4).contains(&v)).then_some((h, v))
+                    })
+                    .collect();
+            }
+            _ => {
+                let bytes = data.get(pos..pos + 2)?;
+                pos += usize::from(u16::from_be_bytes([bytes[0], bytes[1]]));
+            }
+        }
+    }
+}
+
+/// 从`start`开始查找熵编码数据的结束位置，即下一个不是RSTn的标记
+fn scan_data_end(data: &[u8], start: usize) -> usize {
+    let mut pos = start;
+    while pos + 1 < data.len() {
+        // 熵编码数据中的0xFF后只会是填充的0x00或者RSTn，其他情况都是标记(包括标记前填充的0xFF)
+        if data[pos] == 0xFF && data[pos + 1] != 0x00 && !(0xD0..=0xD7).contains(&data[pos + 1]) {
+            return pos;
+        }
+        pos += 1;
+    }
+    data.len()
+}
+
+/// 解析SOF段，返回(宽度, 高度, 所有分量)，不支持时返回`None`
+fn parse_frame(payload: &[u8]) -> anyhow::Result<Option<(usize, usize, Vec<Component>)>> {
+    let header = payload.get(..6).ok_or(anyhow!("SOF段的长度无效"))?;
+    let precision = header[0];
+    let height = usize::from(u16::from_be_bytes([header[1], header[2]]));
+    let width = usize::from(u16::from_be_bytes([header[3], header[4]]));
+    let component_count = usize::from(header[5]);
+    // 高度为0时需要DNL段给出高度，很少见
+    if precision != 8 || width == 0 || height == 0 || !matches!(component_count, 1 | 3) {
+        return Ok(None);
+    }
+    let mut components = Vec::with_capacity(component_count);
+    for i in 0..component_count {
+        let bytes = payload
+            .get(6 + i * 3..9 + i * 3)
+            .ok_or(anyhow!("SOF段的长度无效"))?;
+        let (h, v) = (usize::from(bytes[1] >> 4), usize::from(bytes[1] & 0x0F));
+        let quant_table = usize::from(bytes[2]);
+        if !(1..=4).contains(&h) || !(1..=4).contains(&v) || quant_table >= 4 {
+            return Err(anyhow!("分量{}的采样因子或量化表无效", bytes[0]));
+        }
+        components.push(Component {
+            id: bytes[0],
+            h,
+            v,
+            quant_table,
+            dc_table: 0,
+            ac_table: 0,
+            blocks_per_line: 0,
+            blocks: vec![],
+        });
+    }
+    // 分量ID为R、G、B时是RGB而不是YCbCr
+    let ids: Vec<u8> = components.iter().map(|c| c.id).collect();
+    if ids == b"RGB" {
+        return Ok(None);
+    }
+    // 采样因子必须能整除最大的采样因子，否则色度的采样位置不是整数
+    let max_h = components.iter().map(|c| c.h).max().unwrap_or(1);
+    let max_v = components.iter().map(|c| c.v).max().unwrap_or(1);
+    if components
+        .iter()
+        .any(|c| max_h % c.h != 0 || max_v % c.v != 0)
+    {
+        return Ok(None);
+    }
+    Ok(Some((width, height, components)))
+}
+
+/// 解析SOS段，记录每个分量使用的霍夫曼表，不支持这次扫描时返回false
+fn parse_scan(payload: &[u8], components: &mut [Component]) -> anyhow::Result<bool> {
+    let component_count = usize::from(*payload.first().context("SOS段的长度无效")?);
+    // 所有分量必须在同一次扫描中
+    if component_count != components.len() {
+        return Ok(false);
+    }
+    for (i, component) in components.iter_mut().enumerate() {
+        let bytes = payload
+            .get(1 + i * 2..3 + i * 2)
+            .ok_or(anyhow!("SOS段的长度无效"))?;
+        if bytes[0] != component.id {
+            return Ok(false);
+        }
+        component.dc_table = usize::from(bytes[1] >> 4);
+        component.ac_table = usize::from(bytes[1] & 0x0F);
+        if component.dc_table >= 4 || component.ac_table >= 4 {
+            return Err(anyhow!("分量{}的霍夫曼表无效", component.id));
+        }
+    }
+    // 顺序JPEG的频谱范围必须是0到63，逐次逼近位必须是0
+    let spectral = payload
+        .get(1 + component_count * 2..4 + component_count * 2)
+        .ok_or(anyhow!("SOS段的长度无效"))?;
+    Ok(spectral == [0, 63, 0])
+}
+
+/// 解析DHT段中的所有霍夫曼表
+fn parse_huffman_tables(
+    payload: &[u8],
+    dc_tables: &mut [Option<HuffmanTable>; 4],
+    ac_tables: &mut [Option<HuffmanTable>; 4],
+) -> anyhow::Result<()> {
+    let mut pos = 0;
+    while pos < payload.len() {
+        let class = payload[pos] >> 4;
+        let id = usize::from(payload[pos] & 0x0F);
+        let counts: [u8; 16] = payload
+            .get(pos + 1..pos + 17)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(anyhow!("DHT段的长度无效"))?;
+        let value_count: usize = counts.iter().map(|&count| usize::from(count)).sum();
+        let values = payload
+            .get(pos + 17..pos + 17 + value_count)
+            .ok_or(anyhow!("DHT段的长度无效"))?
+            .to_vec();
+        let tables = match class {
+            0 => &mut *dc_tables,
+            1 => &mut *ac_tables,
+            _ => return Err(anyhow!("霍夫曼表的类型{class}无效")),
+        };
+        let table = tables.get_mut(id).ok_or(anyhow!("霍夫曼表的ID{id}无效"))?;
+        *table = Some(HuffmanTable { counts, values });
+        pos += 17 + value_count;
+    }
+    Ok(())
+}
+
+/// 解析DQT段中的所有量化表
+fn parse_quant_tables(
+    payload: &[u8],
+    quant_tables: &mut [Option<[u16; 64]>; 4],
+) -> anyhow::Result<()> {
+    let mut pos = 0;
+    while pos < payload.len() {
+        let precision = payload[pos] >> 4;
+        let id = usize::from(payload[pos] & 0x0F);
+        let mut table = [0; 64];
+        let value_size = if precision == 0 { 1 } else { 2 };
+        let bytes = payload
+            .get(pos + 1..pos + 1 + 64 * value_size)
+            .ok_or(anyhow!("DQT段的长度无效"))?;
+        for (value, bytes) in table.iter_mut().zip(bytes.chunks_exact(value_size)) {
+            *value = match bytes {
+                [byte] => u16::from(*byte),
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                _ => unreachable!(),
+            };
+        }
+        if table.contains(&0) {
+            return Err(anyhow!("量化表{id}中有为0的值"));
+        }
+        let slot = quant_tables
+            .get_mut(id)
+            .ok_or(anyhow!("量化表的ID{id}无效"))?;
+        *slot = Some(table);
+        pos += 1 + 64 * value_size;
+    }
+    Ok(())
+}
@@ -0,0 +1,260 @@
+use anyhow::anyhow;
+
+use crate::jpeg::bits::{BitReader, BitWriter};
+use crate::jpeg::huffman::{HuffmanDecoder, HuffmanTable};
+use crate::jpeg::parse::JpegFile;
+
+/// 熵编码数据中的一项
+enum Token {
+    Symbol(Symbol),
+    /// 重置间隔结束，参数为重置标记的序号
+    Restart(usize),
+}
+
+/// 熵编码的一个符号，以及紧随其后的附加位
+struct Symbol {
+    /// 是否是DC系数的符号
+    dc: bool,
+    /// 使用的霍夫曼表的ID
+    table: usize,
+    /// DC系数为差值的位数，AC系数为(前面0的个数 << 4) | 位数
+    value: u8,
+    /// 附加位的位数
+    size: u8,
+    /// 附加位
+    bits: u16,
+}
+
+/// 解码`file`的熵编码数据，把每个块量化后的DCT系数保存到对应分量的`blocks`中
+pub(super) fn decode_scan(file: &mut JpegFile) -> anyhow::Result<()> {
+    let decoders = |tables: &[Option<HuffmanTable>; 4]| -> Vec<Option<HuffmanDecoder>> {
+        tables
+            .iter()
+            .map(|table| table.as_ref().map(HuffmanTable::decoder))
+            .collect()
+    };
+    let dc_decoders = decoders(&file.dc_tables);
+    let ac_decoders = decoders(&file.ac_tables);
+    let mut reader = BitReader::new(file.scan_data);
+    let mut predictions = vec![0_i32; file.components.len()];
+    let mcu_count = file.mcus_per_line * file.mcus_per_column;
+    for mcu in 0..mcu_count {
+        if file.restart_interval > 0 && mcu > 0 && mcu % file.restart_interval == 0 {
+            reader.restart()?;
+            predictions.fill(0);
+        }
+        let (mcu_x, mcu_y) = (mcu % file.mcus_per_line, mcu / file.mcus_per_line);
+        for (component, prediction) in file.components.iter_mut().zip(&mut predictions) {
+            let (Some(dc_decoder), Some(ac_decoder)) = (
+                &dc_decoders[component.dc_table],
+                &ac_decoders[component.ac_table],
+            ) else {
+                return Err(anyhow!("分量{}使用的霍夫曼表不存在", component.id));
+            };
+            for block_y in 0..component.v {
+                for block_x in 0..component.h {
+                    let x = mcu_x * component.h + block_x;
+                    let y = mcu_y * component.v + block_y;
+                    let block = &mut component.blocks[y * component.blocks_per_line + x];
+                    decode_block(&mut reader, dc_decoder, ac_decoder, prediction, block)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 解码一个块，`prediction`是同一分量上一个块的DC系数
+#[allow(clippy::cast_possible_truncation)]
+fn decode_block(
+    reader: &mut BitReader,
+    dc_decoder: &HuffmanDecoder,
+    ac_decoder: &HuffmanDecoder,
+    prediction: &mut i32,
+    block: &mut [i16; 64],
+) -> anyhow::Result<()> {
+    let size = dc_decoder.decode(reader)?;
+    *prediction += extend(reader.read_bits(size), size);
+    block[0] = *prediction as i16;
+    let mut k = 1;
+    while k < 64 {
+        let value = ac_decoder.decode(reader)?;
+        let (run, size) = (usize::from(value >> 4), value & 0x0F);
+        if size == 0 {
+            // 0xF0表示16个0，其他表示之后全是0
+            if run != 15 {
+                break;
+            }
+            k += 16;
+            continue;
+        }
+        k += run;
+        let coefficient = block.get_mut(k).ok_or(anyhow!("块中的AC系数超过了63个"))?;
+        *coefficient = extend(reader.read_bits(size), size) as i16;
+        k += 1;
+    }
+    Ok(())
+}
+
+/// 重新编码`file`中所有块的系数，根据实际出现的符号生成最优的霍夫曼表
+///
+/// 返回(DC表, AC表, 熵编码数据)，没有被用到的表为`None`
+pub(super) fn encode_scan(
+    file: &JpegFile,
+) -> (
+    [Option<HuffmanTable>; 4],
+    [Option<HuffmanTable>; 4],
+    Vec<u8>,
+) {
+    // 第一遍统计每个符号出现的次数
+    let mut dc_frequencies = [[0_u32; 256]; 4];
+    let mut ac_frequencies = [[0_u32; 256]; 4];
+    let mut dc_used = [false; 4];
+    let mut ac_used = [false; 4];
+    for_each_token(file, |token| {
+        let Token::Symbol(symbol) = token else {
+            return;
+        };
+        let (frequencies, used) = if symbol.dc {
+            (&mut dc_frequencies, &mut dc_used)
+        } else {
+            (&mut ac_frequencies, &mut ac_used)
+        };
+        frequencies[symbol.table][usize::from(symbol.value)] += 1;
+        used[symbol.table] = true;
+    });
+    let optimal_tables = |frequencies: &[[u32; 256]; 4], used: &[bool; 4]| {
+        let mut tables: [Option<HuffmanTable>; 4] = Default::default();
+        for (i, table) in tables.iter_mut().enumerate() {
+            if used[i] {
+                *table = Some(HuffmanTable::optimal(&frequencies[i]));
+            }
+        }
+        tables
+    };
+    let dc_tables = optimal_tables(&dc_frequencies, &dc_used);
+    let ac_tables = optimal_tables(&ac_frequencies, &ac_used);
+
+    // 第二遍用生成的霍夫曼表编码
+    let encoders = |tables: &[Option<HuffmanTable>; 4]| {
+        tables
+            .iter()
+            .map(|table| table.as_ref().map(HuffmanTable::encoder))
+            .collect::<Vec<_>>()
+    };
+    let dc_encoders = encoders(&dc_tables);
+    let ac_encoders = encoders(&ac_tables);
+    let mut writer = BitWriter::new();
+    for_each_token(file, |token| match token {
+        Token::Symbol(symbol) => {
+            let encoders = if symbol.dc {
+                &dc_encoders
+            } else {
+                &ac_encoders
+            };
+            // 第一遍已经为用到的每张表生成了编码
+            if let Some(encoder) = &encoders[symbol.table] {
+                let (code, len) = encoder.code(symbol.value);
+                writer.write_bits(u32::from(code), len);
+                writer.write_bits(u32::from(symbol.bits), symbol.size);
+            }
+        }
+        Token::Restart(index) => writer.write_restart(index),
+    });
+    (dc_tables, ac_tables, writer.into_bytes())
+}
+
+/// 按照熵编码的顺序遍历`file`中所有块的符号和重置标记
+fn for_each_token(file: &JpegFile, mut on_token: impl FnMut(Token)) {
+    let mut predictions = vec![0_i32; file.components.len()];
+    let mcu_count = file.mcus_per_line * file.mcus_per_column;
+    let mut restart_index = 0;
+    for mcu in 0..mcu_count {
+        if file.restart_interval > 0 && mcu > 0 && mcu % file.restart_interval == 0 {
+            on_token(Token::Restart(restart_index));
+            restart_index += 1;
+            predictions.fill(0);
+        }
+        let (mcu_x, mcu_y) = (mcu % file.mcus_per_line, mcu / file.mcus_per_line);
+        for (component, prediction) in file.components.iter().zip(&mut predictions) {
+            for block_y in 0..component.v {
+                for block_x in 0..component.h {
+                    let x = mcu_x * component.h + block_x;
+                    let y = mcu_y * component.v + block_y;
+                    let block = &component.blocks[y * component.blocks_per_line + x];
+
+                    let difference = i32::from(block[0]) - *prediction;
+                    *prediction = i32::from(block[0]);
+                    let (size, bits) = magnitude(difference);
+                    on_token(Token::Symbol(Symbol {
+                        dc: true,
+                        table: component.dc_table,
+                        value: size,
+                        size,
+                        bits,
+                    }));
+
+                    let mut run = 0;
+                    for &coefficient in &block[1..] {
+                        if coefficient == 0 {
+                            run += 1;
+                            continue;
+                        }
+                        while run >= 16 {
+                            on_token(Token::Symbol(Symbol {
+                                dc: false,
+                                table: component.ac_table,
+                                value: 0xF0,
+                                size: 0,
+                                bits: 0,
+                            }));
+                            run -= 16;
+                        }
+                        let (size, bits) = magnitude(i32::from(coefficient));
+                        on_token(Token::Symbol(Symbol {
+                            dc: false,
+                            table: component.ac_table,
+                            value: (run << 4) | size,
+                            size,
+                            bits,
+                        }));
+                        run = 0;
+                    }
+                    // 块的末尾是0时写入EOB
+                    if run > 0 {
+                        on_token(Token::Symbol(Symbol {
+                            dc: false,
+                            table: component.ac_table,
+                            value: 0x00,
+                            size: 0,
+                            bits: 0,
+                        }));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 把`size`位的附加位`bits`还原为系数，最高位为0时表示负数
+#[allow(clippy::cast_possible_wrap)]
+fn extend(bits: u32, size: u8) -> i32 {
+    if size == 0 {
+        return 0;
+    }
+    let bits = bits as i32;
+    if bits < 1 << (size - 1) {
+        bits - (1 << size) + 1
+    } else {
+        bits
+    }
+}
+
+/// 计算系数`value`的(位数, 附加位)，与`extend`互逆
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+fn magnitude(value: i32) -> (u8, u16) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    let bits = if value < 0 { value - 1 } else { value };
+    (size, (bits as u32 & ((1 << size) - 1)) as u16)
+}
@@ -14,8 +14,11 @@ mod config;
 mod errors;
 mod events;
 mod extensions;
+mod jpeg;
+mod metadata;
 mod types;
 mod utils;
+mod watermark;
 
 fn generate_context() -> Context<Wry> {
     tauri::generate_context!()
@@ -25,13 +28,18 @@ fn main() {
     let builder = tauri_specta::Builder::<Wry>::new()
         .commands(tauri_specta::collect_commands![
             generate_background,
+            detect_watermark_rect,
+            get_background_candidates,
+            generate_background_from_pair,
+            cancel_generate_background,
             remove_watermark,
             open_image,
             get_manga_dir_data,
-            get_jpg_image_infos,
+            get_image_infos,
             show_path_in_file_manager,
             get_background_dir_relative_path,
             get_background_dir_abs_path,
+            get_background_variants,
             get_config,
             save_config,
         ])
@@ -40,6 +48,9 @@ fn main() {
             RemoveWatermarkSuccessEvent,
             RemoveWatermarkErrorEvent,
             RemoveWatermarkEndEvent,
+            GenerateBackgroundStartEvent,
+            GenerateBackgroundProgressEvent,
+            GenerateBackgroundEndEvent,
         ]);
     // 只有在debug模式下才会生成bindings.ts
     #[cfg(debug_assertions)]
@@ -62,6 +73,7 @@ fn main() {
             builder.mount_events(app);
             let config = RwLock::new(Config::new(app.handle())?);
             app.manage(config);
+            app.manage(GenerateBackgroundCancellation::default());
             Ok(())
         })
         .run(generate_context())
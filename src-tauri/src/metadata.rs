@@ -0,0 +1,462 @@
+use std::io::{Cursor, Write};
+
+use anyhow::Context;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::{ImageDecoder, ImageReader};
+
+/// JPEG的APP1段和部分WebP的EXIF块中EXIF数据的前缀
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+/// PNG文件的签名
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// JPEG的APPn段中数据的最大长度，段的长度用16位整数保存，并且包含长度本身的2字节
+const MAX_SEGMENT_DATA_LEN: usize = u16::MAX as usize - 2;
+/// 删除元数据时要删除的PNG块
+const PNG_METADATA_CHUNKS: [&[u8]; 6] = [b"tEXt", b"zTXt", b"iTXt", b"eXIf", b"iCCP", b"tIME"];
+/// 删除元数据时要删除的WebP块
+const WEBP_METADATA_CHUNKS: [&[u8]; 3] = [b"ICCP", b"EXIF", b"XMP "];
+/// WebP的VP8X块中表示存在ICC、EXIF、XMP块的标志位
+const VP8X_METADATA_FLAGS: u8 = 0x20 | 0x08 | 0x04;
+
+/// 图片重新编码时需要保留的元数据
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// ICC颜色配置文件
+    pub icc_profile: Option<Vec<u8>>,
+    /// EXIF数据，不包含`Exif\0\0`前缀
+    pub exif: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    /// 读取图片文件数据`data`中的ICC颜色配置文件和EXIF
+    pub fn read(data: &[u8]) -> anyhow::Result<Self> {
+        let mut decoder = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()?
+            .into_decoder()
+            .context("创建解码器失败")?;
+        let icc_profile = decoder.icc_profile().context("读取ICC颜色配置文件失败")?;
+        Ok(Self {
+            icc_profile,
+            exif: read_exif(data),
+        })
+    }
+
+    /// 把元数据添加到JPEG编码器`encoder`中，EXIF超过一个APP1段的上限时舍弃
+    pub fn add_to_jpeg_encoder<W: jpeg_encoder::JfifWrite>(
+        &self,
+        encoder: &mut jpeg_encoder::Encoder<W>,
+    ) -> anyhow::Result<()> {
+        if let Some(icc_profile) = &self.icc_profile {
+            encoder
+                .add_icc_profile(icc_profile)
+                .context("添加ICC颜色配置文件失败")?;
+        }
+        if let Some(exif) = &self.exif {
+            let segment = [EXIF_HEADER, exif].concat();
+            if segment.len() <= MAX_SEGMENT_DATA_LEN {
+                encoder
+                    .add_app_segment(1, &segment)
+                    .context("添加EXIF失败")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 把元数据插入到PNG文件数据`png`的IHDR块之后，`png`不是PNG时原样返回
+    pub fn insert_into_png(&self, png: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        let Some(ihdr) = png_chunks(&png).and_then(|chunks| chunks.into_iter().next()) else {
+            return Ok(png);
+        };
+        let ihdr_end = PNG_SIGNATURE.len() + ihdr.bytes.len();
+        let mut out = png[..ihdr_end].to_vec();
+        if let Some(icc_profile) = &self.icc_profile {
+            // 配置文件名称 + 0 + 压缩方法(0，zlib) + 压缩后的配置文件
+            let mut payload = b"ICC Profile\0\0".to_vec();
+            let mut encoder = ZlibEncoder::new(&mut payload, Compression::default());
+            encoder.write_all(icc_profile)?;
+            encoder.finish()?;
+            write_png_chunk(&mut out, *b"iCCP", &payload);
+        }
+        if let Some(exif) = &self.exif {
+            write_png_chunk(&mut out, *b"eXIf", exif);
+        }
+        out.extend(&png[ihdr_end..]);
+        Ok(out)
+    }
+}
+
+/// 删除图片文件数据`data`中的所有元数据，返回新的文件数据，不是JPEG、PNG、WebP或者格式不正确时返回`None`
+///
+/// JPEG删除所有APPn段和COM段，但保留影响颜色解码的Adobe APP14段  
+/// PNG删除文本、EXIF、ICC和修改时间块，WebP删除ICC、EXIF和XMP块
+pub fn strip(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        strip_jpeg(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        strip_png(data)
+    } else if is_webp(data) {
+        strip_webp(data)
+    } else {
+        None
+    }
+}
+
+/// 文件中的一个段或者块
+struct Chunk<'a> {
+    /// JPEG为标记，PNG和WebP为块的类型
+    id: &'a [u8],
+    /// 段或者块的数据
+    payload: &'a [u8],
+    /// 整个段或者块，包括标记、长度和校验码等
+    bytes: &'a [u8],
+}
+
+/// 读取图片文件数据`data`中的EXIF，没有EXIF时返回`None`
+fn read_exif(data: &[u8]) -> Option<Vec<u8>> {
+    let exif = if data.starts_with(&[0xFF, 0xD8]) {
+        let (segments, _) = jpeg_segments(data)?;
+        segments
+            .into_iter()
+            .find(|segment| segment.id == [0xE1] && segment.payload.starts_with(EXIF_HEADER))?
+            .payload
+    } else if data.starts_with(PNG_SIGNATURE) {
+        png_chunks(data)?
+            .into_iter()
+            .find(|chunk| chunk.id == b"eXIf")?
+            .payload
+    } else if is_webp(data) {
+        webp_chunks(data)?
+            .into_iter()
+            .find(|chunk| chunk.id == b"EXIF")?
+            .payload
+    } else {
+        return None;
+    };
+    Some(exif.strip_prefix(EXIF_HEADER).unwrap_or(exif).to_vec())
+}
+
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let (segments, scan) = jpeg_segments(data)?;
+    let mut out = Vec::with_capacity(data.len());
+    // SOI
+    out.extend([0xFF, 0xD8]);
+    for segment in segments {
+        let marker = segment.id[0];
+        let is_app = (0xE0..=0xEF).contains(&marker);
+        let is_adobe = marker == 0xEE && segment.payload.starts_with(b"Adobe");
+        let is_comment = marker == 0xFE;
+        if (is_app && !is_adobe) || is_comment {
+            continue;
+        }
+        out.extend(segment.bytes);
+    }
+    out.extend(scan);
+    Some(out)
+}
+
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = PNG_SIGNATURE.to_vec();
+    for chunk in png_chunks(data)? {
+        if !PNG_METADATA_CHUNKS.contains(&chunk.id) {
+            out.extend(chunk.bytes);
+        }
+    }
+    Some(out)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks = vec![];
+    for chunk in webp_chunks(data)? {
+        if WEBP_METADATA_CHUNKS.contains(&chunk.id) {
+            continue;
+        }
+        let mut bytes = chunk.bytes.to_vec();
+        if chunk.id == b"VP8X" {
+            // 块头8字节之后的第1个字节是标志位
+            *bytes.get_mut(8)? &= !VP8X_METADATA_FLAGS;
+        }
+        chunks.push(bytes);
+    }
+    let body: Vec<u8> = chunks.concat();
+    let mut out = Vec::with_capacity(body.len() + 12);
+    out.extend(b"RIFF");
+    // RIFF的大小包括"WEBP"这4个字节
+    out.extend(((body.len() + 4) as u32).to_le_bytes());
+    out.extend(b"WEBP");
+    out.extend(body);
+    Some(out)
+}
+
+/// 把JPEG文件数据`data`拆分为SOS之前的所有段和从SOS开始的剩余数据，格式不正确时返回`None`
+fn jpeg_segments(data: &[u8]) -> Option<(Vec<Chunk<'_>>, &[u8])> {
+    let mut segments = vec![];
+    // 跳过SOI
+    let mut pos = 2;
+    loop {
+        let start = pos;
+        if *data.get(pos)? != 0xFF {
+            return None;
+        }
+        // 标记之前可能有多个用于填充的0xFF
+        while *data.get(pos + 1)? == 0xFF {
+            pos += 1;
+        }
+        let marker = data.get(pos + 1..pos + 2)?;
+        match marker[0] {
+            // SOS，之后是熵编码数据
+            0xDA => return Some((segments, &data[start..])),
+            // EOI
+            0xD9 => return None,
+            // 没有长度的标记
+            0x01 | 0xD0..=0xD7 => {
+                pos += 2;
+                segments.push(Chunk {
+                    id: marker,
+                    payload: &[],
+                    bytes: &data[start..pos],
+                });
+            }
+            _ => {
+                let len = usize::from(u16::from_be_bytes([
+                    *data.get(pos + 2)?,
+                    *data.get(pos + 3)?,
+                ]));
+                let end = pos + 2 + len;
+                segments.push(Chunk {
+                    id: marker,
+                    payload: data.get(pos + 4..end)?,
+                    bytes: data.get(start..end)?,
+                });
+                pos = end;
+            }
+        }
+    }
+}
+
+/// 拆分PNG文件数据`data`中签名之后的所有块，格式不正确时返回`None`
+fn png_chunks(data: &[u8]) -> Option<Vec<Chunk<'_>>> {
+    let mut chunks = vec![];
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let len = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        // 长度 + 类型 + 数据 + CRC
+        let end = pos + 8 + len + 4;
+        chunks.push(Chunk {
+            id: data.get(pos + 4..pos + 8)?,
+            payload: data.get(pos + 8..pos + 8 + len)?,
+            bytes: data.get(pos..end)?,
+        });
+        pos = end;
+    }
+    Some(chunks)
+}
+
+fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP"
+}
+
+/// 拆分WebP文件数据`data`中RIFF头之后的所有块，格式不正确时返回`None`
+fn webp_chunks(data: &[u8]) -> Option<Vec<Chunk<'_>>> {
+    let mut chunks = vec![];
+    let mut pos = 12;
+    while pos < data.len() {
+        let len = u32::from_le_bytes(data.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let payload_end = pos + 8 + len;
+        // 块的数据长度为奇数时补齐一个字节
+        let end = (payload_end + len % 2).min(data.len());
+        chunks.push(Chunk {
+            id: data.get(pos..pos + 4)?,
+            payload: data.get(pos + 8..payload_end)?,
+            bytes: data.get(pos..end)?,
+        });
+        pos = end;
+    }
+    Some(chunks)
+}
+
+/// 把类型为`id`、数据为`payload`的PNG块写入`out`
+#[allow(clippy::cast_possible_truncation)]
+fn write_png_chunk(out: &mut Vec<u8>, id: [u8; 4], payload: &[u8]) {
+    out.extend((payload.len() as u32).to_be_bytes());
+    out.extend(id);
+    out.extend(payload);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&id);
+    hasher.update(payload);
+    out.extend(hasher.finalize().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::png::PngEncoder;
+    use image::codecs::webp::WebPEncoder;
+    use image::{ExtendedColorType, ImageEncoder, RgbImage};
+
+    use super::*;
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn test_image() -> RgbImage {
+        RgbImage::from_fn(16, 16, |x, y| image::Rgb([x as u8 * 16, y as u8 * 16, 128]))
+    }
+
+    fn test_metadata() -> Metadata {
+        Metadata {
+            icc_profile: Some(b"test icc profile".repeat(8)),
+            exif: Some(b"MM\0\x2a\0\0\0\x08\0\0".to_vec()),
+        }
+    }
+
+    fn jpeg_with_metadata(metadata: &Metadata) -> anyhow::Result<Vec<u8>> {
+        let img = test_image();
+        let mut data = vec![];
+        let mut encoder = jpeg_encoder::Encoder::new(&mut data, 90);
+        metadata.add_to_jpeg_encoder(&mut encoder)?;
+        encoder.add_app_segment(15, b"other app segment")?;
+        encoder.encode(img.as_raw(), 16, 16, jpeg_encoder::ColorType::Rgb)?;
+        Ok(data)
+    }
+
+    fn png_with_metadata(metadata: &Metadata) -> anyhow::Result<Vec<u8>> {
+        let img = test_image();
+        let mut data = vec![];
+        PngEncoder::new(&mut data).write_image(img.as_raw(), 16, 16, ExtendedColorType::Rgb8)?;
+        let mut data = metadata.insert_into_png(data)?;
+        // 在IEND之前插入一个文本块
+        let iend = data.len() - 12;
+        let mut text = vec![];
+        write_png_chunk(&mut text, *b"tEXt", b"Comment\0test");
+        data.splice(iend..iend, text);
+        Ok(data)
+    }
+
+    /// 用VP8X块组装带有ICC、EXIF和XMP块的WebP
+    #[allow(clippy::cast_possible_truncation)]
+    fn webp_with_metadata(metadata: &Metadata) -> anyhow::Result<Vec<u8>> {
+        let img = test_image();
+        let mut simple = vec![];
+        WebPEncoder::new_lossless(&mut simple).write_image(
+            img.as_raw(),
+            16,
+            16,
+            ExtendedColorType::Rgb8,
+        )?;
+        let chunk = |id: &[u8], payload: &[u8]| {
+            let mut bytes = id.to_vec();
+            bytes.extend((payload.len() as u32).to_le_bytes());
+            bytes.extend(payload);
+            if payload.len() % 2 == 1 {
+                bytes.push(0);
+            }
+            bytes
+        };
+        // 标志位 + 3字节保留 + 画布宽度减1 + 画布高度减1
+        let mut vp8x = vec![VP8X_METADATA_FLAGS, 0, 0, 0];
+        vp8x.extend([15, 0, 0, 15, 0, 0]);
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", &vp8x));
+        if let Some(icc_profile) = &metadata.icc_profile {
+            body.extend(chunk(b"ICCP", icc_profile));
+        }
+        // 简单格式的WebP在RIFF头之后只有一个图片块
+        body.extend(&simple[12..]);
+        if let Some(exif) = &metadata.exif {
+            body.extend(chunk(b"EXIF", exif));
+        }
+        body.extend(chunk(b"XMP ", b"<x:xmpmeta/>"));
+        let mut data = b"RIFF".to_vec();
+        data.extend((body.len() as u32).to_le_bytes());
+        data.extend(body);
+        Ok(data)
+    }
+
+    fn assert_same_pixels(a: &[u8], b: &[u8]) -> anyhow::Result<()> {
+        let a = image::load_from_memory(a)?.to_rgb8();
+        let b = image::load_from_memory(b)?.to_rgb8();
+        assert_eq!(a, b);
+        Ok(())
+    }
+
+    fn assert_preserved(data: &[u8]) -> anyhow::Result<()> {
+        let expected = test_metadata();
+        let metadata = Metadata::read(data)?;
+        assert_eq!(metadata.icc_profile, expected.icc_profile);
+        assert_eq!(metadata.exif, expected.exif);
+        Ok(())
+    }
+
+    fn assert_stripped(data: &[u8]) -> anyhow::Result<()> {
+        let stripped = strip(data).context("删除元数据失败")?;
+        let metadata = Metadata::read(&stripped)?;
+        assert_eq!(metadata.icc_profile, None);
+        assert_eq!(metadata.exif, None);
+        assert_same_pixels(data, &stripped)
+    }
+
+    #[test]
+    fn jpeg_metadata_is_preserved() -> anyhow::Result<()> {
+        assert_preserved(&jpeg_with_metadata(&test_metadata())?)
+    }
+
+    #[test]
+    fn jpeg_metadata_is_stripped() -> anyhow::Result<()> {
+        let data = jpeg_with_metadata(&test_metadata())?;
+        assert_stripped(&data)?;
+        // 其他APPn段也被删除
+        let stripped = strip(&data).context("删除元数据失败")?;
+        let (segments, _) = jpeg_segments(&stripped).context("拆分JPEG失败")?;
+        assert!(segments
+            .iter()
+            .all(|segment| !(0xE1..=0xEF).contains(&segment.id[0])));
+        Ok(())
+    }
+
+    #[test]
+    fn png_metadata_is_preserved() -> anyhow::Result<()> {
+        let data = png_with_metadata(&test_metadata())?;
+        assert_preserved(&data)?;
+        // 插入元数据后仍然是有效的PNG
+        let mut plain = vec![];
+        let img = test_image();
+        PngEncoder::new(&mut plain).write_image(img.as_raw(), 16, 16, ExtendedColorType::Rgb8)?;
+        assert_same_pixels(&data, &plain)
+    }
+
+    #[test]
+    fn png_metadata_is_stripped() -> anyhow::Result<()> {
+        let data = png_with_metadata(&test_metadata())?;
+        assert_stripped(&data)?;
+        let stripped = strip(&data).context("删除元数据失败")?;
+        let chunks = png_chunks(&stripped).context("拆分PNG失败")?;
+        assert!(chunks
+            .iter()
+            .all(|chunk| !PNG_METADATA_CHUNKS.contains(&chunk.id)));
+        Ok(())
+    }
+
+    #[test]
+    fn webp_metadata_is_preserved() -> anyhow::Result<()> {
+        assert_preserved(&webp_with_metadata(&test_metadata())?)
+    }
+
+    #[test]
+    fn webp_metadata_is_stripped() -> anyhow::Result<()> {
+        let data = webp_with_metadata(&test_metadata())?;
+        assert_stripped(&data)?;
+        let stripped = strip(&data).context("删除元数据失败")?;
+        let chunks = webp_chunks(&stripped).context("拆分WebP失败")?;
+        assert!(chunks
+            .iter()
+            .all(|chunk| !WEBP_METADATA_CHUNKS.contains(&chunk.id)));
+        // VP8X中的元数据标志位被清除
+        let vp8x = chunks.iter().find(|chunk| chunk.id == b"VP8X");
+        let flags = vp8x.and_then(|chunk| chunk.payload.first()).copied();
+        assert_eq!(flags, Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_format_is_not_stripped() {
+        assert!(strip(b"not an image").is_none());
+    }
+}
@@ -5,7 +5,7 @@ use base64::Engine;
 use serde::{Deserialize, Serialize};
 use specta::Type;
 
-#[derive(Debug, Deserialize, Serialize, Type)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Type)]
 pub struct RectData {
     pub left: u32,
     pub top: u32,
@@ -19,24 +19,42 @@ pub struct MangaDirData {
     pub height: u32,
     pub count: u32,
     #[serde(rename = "blackBackground")]
-    pub black_background: Option<JpgImageData>,
+    pub black_background: Option<ImageData>,
     #[serde(rename = "whiteBackground")]
-    pub white_background: Option<JpgImageData>,
+    pub white_background: Option<ImageData>,
+    /// 同一尺寸除第一种以外的其他水印的背景水印图，每种水印对应一对(黑色背景, 白色背景)
+    #[serde(rename = "variantBackgrounds")]
+    pub variant_backgrounds: Vec<(ImageData, ImageData)>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Type)]
-pub struct JpgImageInfo {
+pub struct ImageInfo {
     pub width: u32,
     pub height: u32,
     pub path: PathBuf,
 }
 
+/// 可以用作背景水印图的图片
+#[derive(Debug, Clone, Deserialize, Serialize, Type)]
+pub struct BackgroundCandidate {
+    pub path: PathBuf,
+    /// 截图区域的边上的纯色背景颜色
+    pub color: [u8; 3],
+    /// 截图区域的边上的像素与背景颜色的平均差距，越小说明噪点越少
+    pub noise: f32,
+    /// 截图区域内不是背景颜色的像素比例，即水印覆盖的比例
+    pub coverage: f32,
+}
+
 #[derive(Debug, Deserialize, Serialize, Type)]
-pub struct JpgImageData {
-    pub info: JpgImageInfo,
+pub struct ImageData {
+    pub info: ImageInfo,
+    /// 图片的MIME类型，比如`image/jpeg`、`image/png`
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
     pub base64: String,
 }
-impl JpgImageData {
+impl ImageData {
     pub fn to_image(&self) -> anyhow::Result<image::DynamicImage> {
         let decode = general_purpose::STANDARD.decode(self.base64.as_bytes())?;
         let image = image::load_from_memory(&decode)?;
@@ -49,3 +67,109 @@ pub enum ImageFormat {
     Jpeg,
     Png,
 }
+
+/// 反推原图时使用的颜色空间
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, Type)]
+pub enum InversionMode {
+    /// 在RGB空间中逐通道反推
+    #[default]
+    Rgb,
+    /// 在YCbCr空间中反推，亮度逐像素反推，色度按照JPEG的色度采样分块反推，可以消除彩色图片上水印周围的彩边
+    YCbCr,
+}
+
+/// 以JPEG输出的图片的宽或高超过JPEG的上限(65535)时的处理方式
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, Type)]
+pub enum OversizedJpegPolicy {
+    /// 改为以PNG输出
+    #[default]
+    Png,
+    /// 按照高度切分为多张编号的JPEG，宽度超过上限时仍以PNG输出
+    Split,
+}
+
+/// 图片元数据(EXIF、ICC颜色配置文件、注释等)的处理方式
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, Type)]
+pub enum MetadataPolicy {
+    /// 重新编码的图片保留原图的EXIF和ICC颜色配置文件，原样复制的图片保留所有元数据
+    #[default]
+    Preserve,
+    /// 删除所有图片的元数据，包括原样复制的图片，避免泄露下载工具写入的账号等信息
+    Strip,
+}
+
+/// 判断图片是否满足背景条件时使用的阈值，用于容忍JPEG压缩带来的噪点
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct BackgroundThresholds {
+    /// 像素每个通道与背景颜色的差距都不超过这个值时，认为是背景颜色
+    pub color_tolerance: u8,
+    /// 截图区域的边上是背景颜色的像素不少于这个比例时，认为边上是纯色
+    pub min_solid_border_ratio: f32,
+    /// 截图区域内是背景颜色的像素超过这个比例时，认为截图区域内没有水印
+    pub max_plain_ratio: f32,
+}
+impl Default for BackgroundThresholds {
+    fn default() -> Self {
+        Self {
+            color_tolerance: 8,
+            min_solid_border_ratio: 0.98,
+            max_plain_ratio: 0.9,
+        }
+    }
+}
+
+/// 被截断的通道超过这个比例时，认为去水印失败
+const MAX_CLIPPED_RATIO: f32 = 0.01;
+/// 水印边缘残留的梯度超过去水印前的这个比例时，认为去水印失败
+const MAX_EDGE_RESIDUAL: f32 = 0.75;
+
+/// 单张图片去水印的质量
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovalQuality {
+    /// 水印覆盖的像素中，计算结果明显超出`[0, 255]`而被截断的通道所占的比例
+    pub clipped_ratio: f32,
+    /// 去水印后与去水印前水印边缘处平均梯度的比值，越小说明水印残留越少
+    pub edge_residual: f32,
+}
+impl RemovalQuality {
+    pub fn is_acceptable(self) -> bool {
+        self.clipped_ratio <= MAX_CLIPPED_RATIO && self.edge_residual <= MAX_EDGE_RESIDUAL
+    }
+
+    /// 达标的结果优于不达标的结果，同样达标或同样不达标时，水印残留更少的结果更好
+    pub fn is_better_than(self, other: Self) -> bool {
+        match (self.is_acceptable(), other.is_acceptable()) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => self.edge_residual < other.edge_residual,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct FlaggedImage {
+    pub img_path: PathBuf,
+    pub quality: RemovalQuality,
+}
+
+#[derive(Debug, Deserialize, Serialize, Type)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoveWatermarkReport {
+    /// 尺寸有对应的背景水印图，但检测不到水印而被原样复制的图片
+    pub skipped_img_paths: Vec<PathBuf>,
+    /// 去水印质量不达标，可能去除水印失败的图片
+    pub flagged_images: Vec<FlaggedImage>,
+    /// 第一次去水印质量不达标，经过自动重试后得到更好结果的图片
+    pub retried_img_paths: Vec<PathBuf>,
+    /// 没有对应尺寸的背景水印图，借用其他尺寸的背景水印图(按照右下角对齐移植或者缩放后移植)去除水印的图片
+    pub transplanted_img_paths: Vec<PathBuf>,
+    /// 尺寸超过JPEG的上限，按照`OversizedJpegPolicy`改为以PNG输出或者切分后输出的图片
+    pub oversized_img_paths: Vec<PathBuf>,
+    /// 带有透明通道，为了保留透明通道而改为以PNG输出的图片
+    pub alpha_img_paths: Vec<PathBuf>,
+    /// 读取元数据失败，输出时没有带上元数据的图片
+    pub metadata_failed_img_paths: Vec<PathBuf>,
+}
@@ -27,3 +27,20 @@ pub fn get_background_dir_abs_path(
     let abs_path = resource_dir.join(relative_path);
     Ok(abs_path)
 }
+
+/// 获取第`index`种水印的黑色和白色背景水印图的文件名
+///
+/// 第0种为`black.png`和`white.png`，之后依次为`black-2.png`和`white-2.png`、`black-3.png`和`white-3.png`...
+pub fn get_background_filenames(index: usize) -> (String, String) {
+    if index == 0 {
+        ("black.png".to_string(), "white.png".to_string())
+    } else {
+        let number = index + 1;
+        (format!("black-{number}.png"), format!("white-{number}.png"))
+    }
+}
+
+/// 判断`path`是否为支持的漫画图片，即`image`能够解码的格式(jpg、png、webp等)
+pub fn is_supported_image(path: &Path) -> bool {
+    image::ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
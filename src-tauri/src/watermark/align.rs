@@ -0,0 +1,178 @@
+use crate::watermark::{Page, WatermarkModel};
+
+/// 亚像素平移量的绝对值都小于这个值时，认为整数平移量已经对齐，不需要重新采样
+const MIN_SUBPIXEL_FRACTION: f32 = 0.1;
+/// 亚像素平移量都是这个值的整数倍，用整数记录平移量，避免比较浮点数
+const SUBPIXEL_UNIT: f32 = 1.0 / 16.0;
+/// 搜索亚像素平移量时依次使用的步长，单位为`SUBPIXEL_UNIT`
+const SUBPIXEL_STEPS: [i16; 3] = [4, 2, 1];
+/// 亚像素平移量的绝对值最多为这么多个`SUBPIXEL_UNIT`，即半个像素
+const MAX_SUBPIXEL_UNITS: i16 = 8;
+
+/// 对齐水印的参数
+#[derive(Debug, Clone, Copy)]
+pub struct Alignment {
+    /// 在水印原本的位置附近搜索的最大平移量，为0时不搜索
+    pub search_radius: u32,
+    /// 是否在最好的整数平移量附近进一步估计亚像素平移量
+    pub subpixel: bool,
+}
+
+impl WatermarkModel {
+    /// 从`offsets`中水印残留最少的整数平移量出发搜索亚像素平移量，返回(重新采样的模型, 整数平移量, 估计的水印残留)
+    ///
+    /// 在每个步长下轮流沿水平和垂直方向尝试正负两个平移量，水印残留变少就移动过去，然后缩小步长继续搜索  
+    /// 只用`edge_residual`估计水印残留，不会去水印，亚像素平移量太小或者没有比整数平移量更好时返回`None`
+    pub(super) fn refine_subpixel(
+        &self,
+        page: &Page,
+        offsets: &[(i32, i32)],
+    ) -> Option<(WatermarkModel, (i32, i32), f32)> {
+        let (offset, mut best_residual) = offsets
+            .iter()
+            .filter_map(|&offset| Some((offset, self.edge_residual(page, offset)?)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        let mut fraction = [0_i16; 2];
+        let mut best_model = None;
+        for step in SUBPIXEL_STEPS {
+            for axis in 0..2 {
+                // 正负两个方向都尝试后再移动，避免被较浅的局部最小值吸引
+                let candidates = [-step, step].map(|delta| {
+                    let mut candidate = fraction;
+                    candidate[axis] =
+                        (candidate[axis] + delta).clamp(-MAX_SUBPIXEL_UNITS, MAX_SUBPIXEL_UNITS);
+                    candidate
+                });
+                for candidate in candidates {
+                    if candidate == fraction {
+                        continue;
+                    }
+                    let [fraction_x, fraction_y] = candidate.map(|n| f32::from(n) * SUBPIXEL_UNIT);
+                    let model = self.subpixel_shifted(fraction_x, fraction_y);
+                    let Some(residual) = model.edge_residual(page, offset) else {
+                        continue;
+                    };
+                    if residual < best_residual {
+                        best_residual = residual;
+                        best_model = Some((candidate, model));
+                    }
+                }
+                if let Some((candidate, _)) = &best_model {
+                    fraction = *candidate;
+                }
+            }
+        }
+        if fraction
+            .iter()
+            .all(|&n| f32::from(n).abs() * SUBPIXEL_UNIT < MIN_SUBPIXEL_FRACTION)
+        {
+            return None;
+        }
+        let (_, model) = best_model?;
+        Some((model, offset, best_residual))
+    }
+
+    /// 重新采样得到水印平移了`(fraction_x, fraction_y)`个像素的模型，平移量的绝对值不超过0.5
+    ///
+    /// 新模型沿用原模型的外接矩形和边缘权重，保证不同亚像素平移量下的水印残留可以直接比较
+    pub(super) fn subpixel_shifted(&self, fraction_x: f32, fraction_y: f32) -> Self {
+        // 新模型在(x, y)处的值来自原模型在(x - fraction_x, y - fraction_y)处的值
+        let source = |x: f32, y: f32| (x - fraction_x, y - fraction_y);
+        let mut resampled = self.resampled(self.dimensions(), *self.rect(), 1, source);
+        resampled.edge_weights.clone_from(&self.edge_weights);
+        resampled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use image::{Rgb, RgbImage};
+
+    use crate::types::InversionMode;
+    use crate::watermark::synthetic::{blend, gradient, max_difference, plain_options};
+    use crate::watermark::{best_removal, Alignment, ChromaSubsampling, Page, WatermarkModel};
+
+    #[test]
+    fn offset_search_finds_cropped_watermark() -> anyhow::Result<()> {
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let models = [WatermarkModel::new(
+            &black,
+            &white,
+            plain_options(InversionMode::Rgb),
+        )?];
+        // 图片左边和上边被裁掉了一个像素，水印相对模型向左上平移了一个像素
+        let shifted = |x: u32, y: u32| alpha(x + 1, y + 1);
+        let original = gradient(64, 48);
+        let mut page = Page::Rgb(blend(&original, shifted, color), ChromaSubsampling::NONE);
+
+        let no_search = Alignment {
+            search_radius: 0,
+            subpixel: false,
+        };
+        assert!(best_removal(&models, &page, no_search).is_none());
+        let search = Alignment {
+            search_radius: 1,
+            subpixel: false,
+        };
+        let removal = best_removal(&models, &page, search).context("检测不到水印")?;
+        let rect = removal.rect();
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (39, 29, 56, 40)
+        );
+        removal.apply(&mut page);
+        assert!(max_difference(page.as_raw(), original.as_raw()) <= 1);
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn subpixel_refinement_reduces_residual() -> anyhow::Result<()> {
+        // alpha每6列从0.5逐渐升高到0.9
+        let alpha = |x: f32, y: u32| {
+            if (40.0..=57.0).contains(&x) && (30..=41).contains(&y) {
+                0.5 + 0.08 * ((x - 40.0) % 6.0)
+            } else {
+                1.0
+            }
+        };
+        let color = [230.0, 210.0, 190.0];
+        let aligned = |x: u32, y: u32| alpha(x as f32, y);
+        // 水印向右平移了半个像素，每个像素的alpha是原本相邻两个像素的平均
+        let half_shifted = |x: u32, y: u32| (alpha(x as f32, y) + alpha(x as f32 - 1.0, y)) / 2.0;
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), aligned, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), aligned, color);
+        let models = [WatermarkModel::new(
+            &black,
+            &white,
+            plain_options(InversionMode::Rgb),
+        )?];
+        let page = Page::Rgb(
+            blend(&gradient(64, 48), half_shifted, color),
+            ChromaSubsampling::NONE,
+        );
+
+        let integer = Alignment {
+            search_radius: 1,
+            subpixel: false,
+        };
+        let subpixel = Alignment {
+            search_radius: 1,
+            subpixel: true,
+        };
+        let refined = best_removal(&models, &page, subpixel).context("检测不到水印")?;
+        assert!(refined.quality.is_acceptable());
+        if let Some(integer) = best_removal(&models, &page, integer) {
+            assert!(refined.quality.edge_residual < integer.quality.edge_residual);
+        }
+        Ok(())
+    }
+}
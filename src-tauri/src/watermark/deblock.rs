@@ -0,0 +1,286 @@
+use crate::types::RectData;
+use crate::watermark::ycbcr::LUMA_WEIGHTS;
+use crate::watermark::{ChromaSubsampling, WatermarkModel};
+
+/// JPEG的块大小，色度降采样时色度块在图片上的边长还要乘以采样块的边长
+const BLOCK_SIZE: u32 = 8;
+/// JPEG量化误差的典型幅度(像素值)，反推后被放大为`gain`倍，多出的`gain - 1`倍就是反推带来的噪点
+const JPEG_NOISE: f32 = 2.0;
+/// 差距在噪点幅度的这么多倍以内时，认为是噪点或块效应而不是图片本身的边缘
+const NOISE_TOLERANCE: f32 = 2.0;
+
+impl WatermarkModel {
+    /// 对去过水印的每行数据`rows`中被水印覆盖的像素去块和降噪，`rows`与`rect_rows`在`rect`处返回的数据一一对应
+    ///
+    /// 反推时JPEG的块效应和噪点被放大为`gain`倍，所以处理强度随`gain`增大，水印越不透明处理得越强，水印外的像素不受影响  
+    /// 先平滑块边界两侧的台阶，再把每个像素与周围差距不大的像素取平均，差距较大的像素视为图片本身的边缘而不参与平均  
+    /// 亮度块的边界每隔8个像素一条，色度块的边界按照原图的色度采样`subsampling`放大(4:2:0时每隔16个像素一条)，
+    /// 所以只在色度块的边界上平滑整个台阶，其他亮度块的边界上只平滑台阶的亮度部分  
+    /// 需要修补的像素之后会被覆盖，所以既不处理也不参与平均
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    pub(super) fn deblock_rows(
+        &self,
+        rows: &mut [Vec<u8>],
+        rect: &RectData,
+        subsampling: ChromaSubsampling,
+    ) {
+        let rect_width = self.rect_width();
+        let channels = self.channels();
+        let row_len = rect_width * channels;
+        // 每个像素每个通道可以容忍的差距，不需要处理的像素为0
+        let tolerance: Vec<f32> = self
+            .gain
+            .iter()
+            .enumerate()
+            .map(|(i, gain)| {
+                let pixel = i / channels;
+                if self.mask[pixel] && !self.inpaint_mask[pixel] {
+                    NOISE_TOLERANCE * JPEG_NOISE * (gain - 1.0).max(0.0)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let mut values: Vec<f32> = rows.iter().flatten().map(|&v| f32::from(v)).collect();
+
+        // 块边界在整张图片上每隔8个像素一条，每条边界处取跨过边界的4个像素[p1, p0, q0, q1]，p1和q1可能超出矩形
+        // 同时记录这条边界是否也是色度块的边界，单通道的模型没有色度，每条边界都平滑整个台阶
+        let rect_height = rows.len();
+        let pixel_at = |row: usize, col: usize| row * rect_width + col;
+        let is_chroma_edge = |position: u32, block_size: u32| {
+            channels == 1 || position.is_multiple_of(BLOCK_SIZE * block_size)
+        };
+        let vertical_edges = (1..rect_width)
+            .map(|col| (col, rect.left + col as u32))
+            .filter(|&(_, x)| x.is_multiple_of(BLOCK_SIZE))
+            .flat_map(|(col, x)| {
+                let chroma = is_chroma_edge(x, subsampling.width);
+                (0..rect_height).map(move |row| {
+                    let edge = [
+                        col.checked_sub(2).map(|col| pixel_at(row, col)),
+                        Some(pixel_at(row, col - 1)),
+                        Some(pixel_at(row, col)),
+                        (col + 1 < rect_width).then(|| pixel_at(row, col + 1)),
+                    ];
+                    (edge, chroma)
+                })
+            });
+        let horizontal_edges = (1..rect_height)
+            .map(|row| (row, rect.top + row as u32))
+            .filter(|&(_, y)| y.is_multiple_of(BLOCK_SIZE))
+            .flat_map(|(row, y)| {
+                let chroma = is_chroma_edge(y, subsampling.height);
+                (0..rect_width).map(move |col| {
+                    let edge = [
+                        row.checked_sub(2).map(|row| pixel_at(row, col)),
+                        Some(pixel_at(row - 1, col)),
+                        Some(pixel_at(row, col)),
+                        (row + 1 < rect_height).then(|| pixel_at(row + 1, col)),
+                    ];
+                    (edge, chroma)
+                })
+            });
+        let edges: Vec<([Option<usize>; 4], bool)> =
+            vertical_edges.chain(horizontal_edges).collect();
+        for (edge, chroma) in edges {
+            if chroma {
+                for channel in 0..channels {
+                    let edge = edge.map(|pixel| pixel.map(|pixel| pixel * channels + channel));
+                    smooth_block_edge(&mut values, &tolerance, edge);
+                }
+            } else {
+                smooth_luma_edge(&mut values, &tolerance, edge);
+            }
+        }
+
+        let deblocked = values.clone();
+        for row in 0..rows.len() {
+            for col in 0..rect_width {
+                for channel in 0..channels {
+                    let index = row * row_len + col * channels + channel;
+                    let tolerance_here = tolerance[index];
+                    if tolerance_here <= 0.0 {
+                        continue;
+                    }
+                    let center = deblocked[index];
+                    let mut sum = 0.0;
+                    let mut count = 0.0;
+                    for neighbour_row in row.saturating_sub(1)..(row + 2).min(rows.len()) {
+                        for neighbour_col in col.saturating_sub(1)..(col + 2).min(rect_width) {
+                            let pixel = neighbour_row * rect_width + neighbour_col;
+                            if self.inpaint_mask[pixel] {
+                                continue;
+                            }
+                            let neighbour = deblocked[pixel * channels + channel];
+                            if (neighbour - center).abs() <= tolerance_here {
+                                sum += neighbour;
+                                count += 1.0;
+                            }
+                        }
+                    }
+                    // 中心像素自己一定参与平均，所以count至少为1
+                    values[index] = sum / count;
+                }
+            }
+        }
+
+        for (row, values) in rows.iter_mut().zip(values.chunks_exact(row_len)) {
+            for (value, new_value) in row.iter_mut().zip(values) {
+                *value = new_value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// 平滑块边界两侧的台阶，`edge`是跨过边界的4个通道的下标[p1, p0, q0, q1]，边界在p0和q0之间
+///
+/// 台阶小于两侧像素的容忍度时才视为块效应，把台阶改为跨越边界两侧各两个像素的斜坡，不需要处理的像素保持不变
+fn smooth_block_edge(values: &mut [f32], tolerance: &[f32], edge: [Option<usize>; 4]) {
+    let [_, Some(p0), Some(q0), _] = edge else {
+        return;
+    };
+    let tolerance_here = tolerance[p0].min(tolerance[q0]);
+    let difference = values[q0] - values[p0];
+    if tolerance_here <= 0.0 || difference.abs() >= tolerance_here {
+        return;
+    }
+    ramp(values, tolerance, edge, difference);
+}
+
+/// 只平滑RGB像素在块边界两侧台阶的亮度部分，`edge`是跨过边界的4个像素的下标[p1, p0, q0, q1]
+///
+/// 亮度的台阶小于两侧所有通道的容忍度时，每个通道减去同样的亮度台阶，色度保持不变
+fn smooth_luma_edge(values: &mut [f32], tolerance: &[f32], edge: [Option<usize>; 4]) {
+    let [_, Some(p0), Some(q0), _] = edge else {
+        return;
+    };
+    let channel = |pixel: usize, channel: usize| pixel * 3 + channel;
+    let tolerance_here = (0..3)
+        .map(|i| tolerance[channel(p0, i)].min(tolerance[channel(q0, i)]))
+        .fold(f32::INFINITY, f32::min);
+    let difference: f32 = (0..3)
+        .map(|i| LUMA_WEIGHTS[i] * (values[channel(q0, i)] - values[channel(p0, i)]))
+        .sum();
+    if tolerance_here <= 0.0 || difference.abs() >= tolerance_here {
+        return;
+    }
+    for i in 0..3 {
+        let edge = edge.map(|pixel| pixel.map(|pixel| channel(pixel, i)));
+        ramp(values, tolerance, edge, difference);
+    }
+}
+
+/// 把`edge`上4个通道的下标[p1, p0, q0, q1]处高度为`difference`的台阶改为斜坡，不需要处理的通道保持不变
+fn ramp(
+    values: &mut [f32],
+    tolerance: &[f32],
+    [p1, p0, q0, q1]: [Option<usize>; 4],
+    difference: f32,
+) {
+    // 斜坡上4个像素的调整量之和为0，保持整体亮度不变
+    let amounts = [1.0, 3.0, -3.0, -1.0].map(|weight| difference * weight / 8.0);
+    for (index, amount) in [p1, p0, q0, q1].into_iter().zip(amounts) {
+        if let Some(index) = index.filter(|&index| tolerance[index] > 0.0) {
+            values[index] += amount;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::types::InversionMode;
+    use crate::watermark::synthetic::{blend, plain_options};
+    use crate::watermark::ModelOptions;
+
+    /// 覆盖32x16图片上(2, 2)到(29, 13)、alpha为0.2的水印的去块模型，反推时噪点被放大为5倍
+    fn opaque_model() -> anyhow::Result<WatermarkModel> {
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (2..=29, 2..=13) => 0.2,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(32, 16, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(32, 16, Rgb([255; 3])), alpha, color);
+        let options = ModelOptions {
+            deblocking: true,
+            ..plain_options(InversionMode::Rgb)
+        };
+        WatermarkModel::new(&black, &white, options)
+    }
+
+    /// 按照`pixel(x)`生成`rect`内每行相同的数据，每行按照`subsampling`去块后返回
+    fn deblocked(
+        model: &WatermarkModel,
+        pixel: impl Fn(u32) -> [u8; 3],
+        subsampling: ChromaSubsampling,
+    ) -> Vec<Vec<u8>> {
+        let rect = *model.rect();
+        let row: Vec<u8> = (rect.left..=rect.right).flat_map(pixel).collect();
+        let mut rows = vec![row; (rect.bottom - rect.top + 1) as usize];
+        model.deblock_rows(&mut rows, &rect, subsampling);
+        rows
+    }
+
+    #[test]
+    fn small_steps_are_smoothed_and_large_edges_kept() -> anyhow::Result<()> {
+        let model = opaque_model()?;
+        // x = 16处亮度的台阶在容忍度以内，是块效应
+        let step = |x: u32| if x < 16 { [100; 3] } else { [104; 3] };
+        let rows = deblocked(&model, step, ChromaSubsampling::NONE);
+        // 第0列是x = 2，边界两侧的p0和q0是第13列和第14列
+        let (p0, q0) = (rows[5][13 * 3], rows[5][14 * 3]);
+        assert!(p0 > 100 && q0 < 104 && q0 - p0 <= 2);
+        // x = 16处的台阶太大，是图片本身的边缘
+        let edge = |x: u32| if x < 16 { [100; 3] } else { [160; 3] };
+        let rows = deblocked(&model, edge, ChromaSubsampling::NONE);
+        let rect = *model.rect();
+        let expected: Vec<u8> = (rect.left..=rect.right).flat_map(edge).collect();
+        assert!(rows.iter().all(|row| *row == expected));
+        Ok(())
+    }
+
+    #[test]
+    fn chroma_steps_are_smoothed_on_the_subsampled_grid() -> anyhow::Result<()> {
+        let model = opaque_model()?;
+        let subsampled = ChromaSubsampling {
+            width: 2,
+            height: 2,
+        };
+        // 每隔8列交替的两种颜色，亮度几乎相同，只有色度的台阶
+        let chroma_steps = |x: u32| {
+            if (x / 8).is_multiple_of(2) {
+                [100, 100, 100]
+            } else {
+                [108, 96, 100]
+            }
+        };
+        let full = deblocked(&model, chroma_steps, ChromaSubsampling::NONE);
+        let halved = deblocked(&model, chroma_steps, subsampled);
+        let red = |rows: &[Vec<u8>], col: usize| rows[5][col * 3];
+        // x = 8不是4:2:0色度块的边界，不平滑色度，只有降噪影响p0和q0，p1和q1保持不变
+        assert_eq!((red(&halved, 4), red(&halved, 7)), (100, 108));
+        assert_ne!((red(&full, 4), red(&full, 7)), (100, 108));
+        // x = 16是色度块的边界，与不降采样时一样平滑
+        assert_eq!(
+            (12..=15).map(|col| red(&halved, col)).collect::<Vec<_>>(),
+            (12..=15).map(|col| red(&full, col)).collect::<Vec<_>>()
+        );
+        // 亮度的台阶在每个亮度块的边界上都平滑
+        let luma_steps = |x: u32| {
+            if (x / 8).is_multiple_of(2) {
+                [100; 3]
+            } else {
+                [104; 3]
+            }
+        };
+        assert_eq!(
+            deblocked(&model, luma_steps, ChromaSubsampling::NONE),
+            deblocked(&model, luma_steps, subsampled)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use std::borrow::Cow;
+
+use crate::types::{RectData, RemovalQuality};
+use crate::watermark::{Alignment, Page, WatermarkModel};
+
+/// 去水印后水印边缘处的梯度小于去水印前的这个比例时，才认为图片上有水印
+const DETECT_RATIO: f32 = 0.95;
+/// 去水印前水印边缘处的平均梯度小于这个值时，认为图片上没有可见的水印
+const MIN_EDGE_ENERGY: f32 = 1.0;
+
+/// 用模型试着去除图片水印的结果，只包含水印外接矩形内的数据
+pub struct WatermarkRemoval<'a> {
+    /// 去水印使用的模型，亚像素对齐时是重新采样得到的新模型
+    model: Cow<'a, WatermarkModel>,
+    /// 去水印的区域，即平移后的水印外接矩形
+    rect: RectData,
+    rows: Vec<Vec<u8>>,
+    pub quality: RemovalQuality,
+}
+
+impl WatermarkRemoval<'_> {
+    /// 去水印的区域，`apply`只会修改这个区域内的像素
+    pub fn rect(&self) -> &RectData {
+        &self.rect
+    }
+
+    /// 去块、降噪(需要时)并修补水印接近不透明的像素后，把去水印的结果写回`page`
+    ///
+    /// 去块、降噪和修补都只在写回时进行，因为检测水印和评估质量都只需要反推的结果
+    pub fn apply(self, page: &mut Page) {
+        let WatermarkRemoval {
+            model,
+            rect,
+            mut rows,
+            ..
+        } = self;
+        if model.options().deblocking {
+            model.deblock_rows(&mut rows, &rect, page.chroma_subsampling());
+        }
+        model.inpaint_rows(&mut rows);
+        for (img_row, row) in model.rect_rows_mut(page, &rect).zip(rows) {
+            img_row.copy_from_slice(&row);
+        }
+    }
+}
+
+impl WatermarkModel {
+    /// 假设图片上的水印相对模型平移了`offset`，估计去水印后与去水印前水印边缘处平均梯度的比值，比值越小说明水印残留越少
+    ///
+    /// 只在水印边缘处逐通道反推，不复制、不反推整个外接矩形，所以可以用来给大量的模型和平移量打分  
+    /// 平移后超出图片范围、图片的尺寸或者通道数量与模型不一致、图片上没有可见的水印时返回`None`
+    #[allow(clippy::cast_precision_loss)]
+    pub(super) fn edge_residual(&self, page: &Page, offset: (i32, i32)) -> Option<f32> {
+        if page.dimensions() != self.dimensions() || page.channels() != self.channels() {
+            return None;
+        }
+        let rect = self.shifted_rect(offset)?;
+        let rows: Vec<&[u8]> = self.rect_rows(page, &rect).collect();
+        let before = self.edge_energy(&rows);
+        if before < MIN_EDGE_ENERGY {
+            return None;
+        }
+        let rect_width = self.rect_width();
+        let channels = self.channels();
+        // 与`invert_rows_per_channel`相同的反推，但不四舍五入，也不统计被截断的通道
+        let inverted = |row: usize, col: usize, i: usize| {
+            let value = f32::from(rows[row][col * channels + i]);
+            let index = row * rect_width + col;
+            if !self.mask[index] {
+                return value;
+            }
+            let plane_index = index * channels + i;
+            (value * self.gain[plane_index] + self.offset[plane_index]).clamp(0.0, 255.0)
+        };
+        let after = self.weighted_gradient(|(row, col), (other_row, other_col)| {
+            let sum: f32 = (0..channels)
+                .map(|i| (inverted(row, col, i) - inverted(other_row, other_col, i)).abs())
+                .sum();
+            sum / channels as f32
+        });
+        Some(after / before)
+    }
+
+    /// 计算水印外接矩形内的数据`rows`在水印边缘处的平均梯度
+    fn edge_energy<R: AsRef<[u8]>>(&self, rows: &[R]) -> f32 {
+        let channels = self.channels();
+        let pixel =
+            |row: usize, col: usize| &rows[row].as_ref()[col * channels..(col + 1) * channels];
+        self.weighted_gradient(|(row, col), (other_row, other_col)| {
+            pixel_difference(pixel(row, col), pixel(other_row, other_col))
+        })
+    }
+
+    /// 计算水印边缘处的平均梯度，每个位置的梯度按照该位置水印边缘的权重加权
+    ///
+    /// `difference((row, col), (other_row, other_col))`为外接矩形内两个相邻像素的差距
+    fn weighted_gradient(&self, difference: impl Fn((usize, usize), (usize, usize)) -> f32) -> f32 {
+        let rect_width = self.rect_width();
+        let mut weighted_sum = 0.0;
+        let mut weight_sum = 0.0;
+        for (index, &[weight_x, weight_y]) in self.edge_weights.iter().enumerate() {
+            let (row, col) = (index / rect_width, index % rect_width);
+            if weight_x > 0.0 {
+                weighted_sum += weight_x * difference((row, col), (row, col + 1));
+                weight_sum += weight_x;
+            }
+            if weight_y > 0.0 {
+                weighted_sum += weight_y * difference((row, col), (row + 1, col));
+                weight_sum += weight_y;
+            }
+        }
+
+        if weight_sum > 0.0 {
+            weighted_sum / weight_sum
+        } else {
+            0.0
+        }
+    }
+}
+
+/// 用`model`去除`page`在平移`offset`后的水印外接矩形内的水印并评估质量，不会修改`page`，平移后超出图片范围时返回`None`
+///
+/// 这里完整地反推整个外接矩形，调用者需要先用`edge_residual`确认图片上有水印
+#[allow(clippy::cast_precision_loss)]
+pub(super) fn remove_with<'a>(
+    model: Cow<'a, WatermarkModel>,
+    page: &Page,
+    offset: (i32, i32),
+) -> Option<WatermarkRemoval<'a>> {
+    let rect = model.shifted_rect(offset)?;
+    // 只复制水印外接矩形内的数据
+    let mut rows: Vec<Vec<u8>> = model.rect_rows(page, &rect).map(<[u8]>::to_vec).collect();
+    let before = model.edge_energy(&rows);
+    let clipped_count = model.invert_rows(&mut rows, &rect, page.chroma_subsampling());
+    let after = model.edge_energy(&rows);
+    let quality = RemovalQuality {
+        clipped_ratio: clipped_count as f32 / (model.watermarked_count() * model.channels()) as f32,
+        edge_residual: if before > 0.0 { after / before } else { 0.0 },
+    };
+    Some(WatermarkRemoval {
+        model,
+        rect,
+        rows,
+        quality,
+    })
+}
+
+/// 用同一尺寸的多个模型分别试着去除`page`的水印，返回水印残留最少的结果，所有模型都检测不到水印时返回`None`
+///
+/// 图片可能被裁剪过一两个像素，所以按照`alignment`在水印原本的位置附近搜索，找出水印残留最少的平移量  
+/// 开启亚像素对齐时，再在最好的整数平移量附近估计亚像素平移量，重新采样模型后再试一次  
+/// 所有模型和平移量都只用`edge_residual`估计水印残留，最后只对残留最少的那个完整地去水印
+#[allow(clippy::cast_possible_wrap)]
+pub fn best_removal<'a>(
+    models: &'a [WatermarkModel],
+    page: &Page,
+    alignment: Alignment,
+) -> Option<WatermarkRemoval<'a>> {
+    let radius = alignment.search_radius as i32;
+    let offsets: Vec<(i32, i32)> = (-radius..=radius)
+        .flat_map(|dy| (-radius..=radius).map(move |dx| (dx, dy)))
+        .collect();
+    let integer_candidates = offsets.iter().flat_map(|&offset| {
+        models.iter().filter_map(move |model| {
+            let residual = model.edge_residual(page, offset)?;
+            Some((Cow::Borrowed(model), offset, residual))
+        })
+    });
+    // 水印平移了半个像素左右时，任何整数平移量都可能检测不到水印，所以每个模型都要尝试亚像素对齐，而不是只对齐最好的结果
+    let subpixel_candidates = models
+        .iter()
+        .filter(|_| alignment.subpixel)
+        .filter_map(|model| model.refine_subpixel(page, &offsets))
+        .map(|(model, offset, residual)| (Cow::Owned(model), offset, residual));
+    let (model, offset, _) = integer_candidates
+        .chain(subpixel_candidates)
+        .filter(|(_, _, residual)| *residual < DETECT_RATIO)
+        .min_by(|(_, _, a), (_, _, b)| a.total_cmp(b))?;
+    remove_with(model, page, offset)
+}
+
+/// 根据水印外接矩形内每个像素每个通道的`gain`，计算每个像素在水平和垂直方向上的水印边缘权重
+///
+/// 权重为相邻像素之间水印不透明度`1 - alpha`的差在各通道上的平均，最右列和最下行没有相邻像素，权重为0
+#[allow(clippy::cast_precision_loss)]
+pub(super) fn edge_weights(rect_width: usize, channels: usize, gain: &[f32]) -> Vec<[f32; 2]> {
+    let opacity: Vec<f32> = gain.iter().map(|gain| 1.0 - 1.0 / gain).collect();
+    let rect_height = opacity.len() / channels / rect_width;
+    let opacity_at = |row: usize, col: usize| {
+        let start = (row * rect_width + col) * channels;
+        &opacity[start..start + channels]
+    };
+    let opacity_difference = |a: &[f32], b: &[f32]| {
+        a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f32>() / channels as f32
+    };
+
+    let mut edge_weights = Vec::with_capacity(rect_width * rect_height);
+    for row in 0..rect_height {
+        for col in 0..rect_width {
+            let current = opacity_at(row, col);
+            let weight_x = if col + 1 < rect_width {
+                opacity_difference(current, opacity_at(row, col + 1))
+            } else {
+                0.0
+            };
+            let weight_y = if row + 1 < rect_height {
+                opacity_difference(current, opacity_at(row + 1, col))
+            } else {
+                0.0
+            };
+            edge_weights.push([weight_x, weight_y]);
+        }
+    }
+    edge_weights
+}
+
+/// 两个像素每个通道差值的绝对值的平均
+#[allow(clippy::cast_precision_loss)]
+fn pixel_difference(a: &[u8], b: &[u8]) -> f32 {
+    let sum: u32 = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| u32::from(a.abs_diff(*b)))
+        .sum();
+    sum as f32 / a.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::types::InversionMode;
+    use crate::watermark::synthetic::{blend, gradient, plain_options};
+    use crate::watermark::ChromaSubsampling;
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn residual_estimate_matches_full_inversion() -> anyhow::Result<()> {
+        // 水印覆盖(40, 30)到(57, 41)，alpha每6列从0.5逐渐升高到0.9，错开一列时大部分边缘仍然可见
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) => 0.5 + 0.08 * ((x - 40) % 6) as f32,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let model = WatermarkModel::new(&black, &white, plain_options(InversionMode::Rgb))?;
+        let page = Page::Rgb(
+            blend(&gradient(64, 48), alpha, color),
+            ChromaSubsampling::NONE,
+        );
+        // 对齐时水印几乎没有残留，错开一列时残留很多，两种情况下估计值都与完整反推的结果一致
+        for offset in [(0, 0), (1, 0)] {
+            let estimate = model
+                .edge_residual(&page, offset)
+                .context("没有可见的水印")?;
+            let removal =
+                remove_with(Cow::Borrowed(&model), &page, offset).context("超出图片范围")?;
+            let exact = removal.quality.edge_residual;
+            assert!(
+                (estimate - exact).abs() < 0.05,
+                "{offset:?}: {estimate} != {exact}"
+            );
+            let rect = removal.rect();
+            let expected_left = 40 + offset.0.unsigned_abs();
+            assert_eq!(
+                (rect.left, rect.top, rect.right),
+                (expected_left, 30, expected_left + 17)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pages_without_visible_watermark_are_skipped() -> anyhow::Result<()> {
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let models = [WatermarkModel::new(
+            &black,
+            &white,
+            plain_options(InversionMode::Rgb),
+        )?];
+        let alignment = Alignment {
+            search_radius: 0,
+            subpixel: false,
+        };
+        // 没有水印的图片，以及已经去过水印的图片
+        let clean = Page::Rgb(gradient(64, 48), ChromaSubsampling::NONE);
+        assert!(best_removal(&models, &clean, alignment).is_none());
+        let mut page = Page::Rgb(
+            blend(&gradient(64, 48), alpha, color),
+            ChromaSubsampling::NONE,
+        );
+        best_removal(&models, &page, alignment)
+            .context("检测不到水印")?
+            .apply(&mut page);
+        assert!(best_removal(&models, &page, alignment).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn best_removal_picks_the_matching_variant() -> anyhow::Result<()> {
+        let color = [230.0, 210.0, 190.0];
+        // 同一尺寸的两种水印，分别在右下角和左上角
+        let bottom_right = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let top_left = |x: u32, y: u32| match (x, y) {
+            (6..=23, 4..=15) if x % 4 < 2 => 0.6,
+            (6..=23, 4..=15) => 0.8,
+            _ => 1.0,
+        };
+        let options = plain_options(InversionMode::Rgb);
+        let mut models = vec![];
+        for alpha in [top_left, bottom_right] {
+            let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+            let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+            models.push(WatermarkModel::new(&black, &white, options)?);
+        }
+        let alignment = Alignment {
+            search_radius: 0,
+            subpixel: false,
+        };
+        let page = Page::Rgb(
+            blend(&gradient(64, 48), bottom_right, color),
+            ChromaSubsampling::NONE,
+        );
+        let removal = best_removal(&models, &page, alignment).context("检测不到水印")?;
+        let rect = removal.rect();
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (40, 30, 57, 41)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use crate::watermark::WatermarkModel;
+
+impl WatermarkModel {
+    /// 修补水印外接矩形内每行数据`rows`中需要修补的像素，`rows`必须已经去过水印
+    ///
+    /// 由外向内逐层修补，每一轮把所有与已知像素相邻的待修补像素设为相邻已知像素的平均值，直到没有可以修补的像素
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn inpaint_rows(&self, rows: &mut [Vec<u8>]) {
+        let rect_width = self.rect_width();
+        let rect_height = rows.len();
+        let channels = self.channels();
+        let mut unknown = self.inpaint_mask.clone();
+        loop {
+            let mut filled = vec![];
+            for row in 0..rect_height {
+                for col in 0..rect_width {
+                    if !unknown[row * rect_width + col] {
+                        continue;
+                    }
+                    // 累加8邻域内所有已知像素，灰度图片只用到第一个通道
+                    let mut sum = [0_u32; 3];
+                    let mut count = 0;
+                    for neighbour_row in row.saturating_sub(1)..(row + 2).min(rect_height) {
+                        for neighbour_col in col.saturating_sub(1)..(col + 2).min(rect_width) {
+                            if unknown[neighbour_row * rect_width + neighbour_col] {
+                                continue;
+                            }
+                            let start = neighbour_col * channels;
+                            let pixel = &rows[neighbour_row][start..start + channels];
+                            for i in 0..channels {
+                                sum[i] += u32::from(pixel[i]);
+                            }
+                            count += 1;
+                        }
+                    }
+                    if count > 0 {
+                        // 四舍五入取平均值
+                        let pixel = sum.map(|sum| ((sum + count / 2) / count) as u8);
+                        filled.push((row, col, pixel));
+                    }
+                }
+            }
+            // 没有可以修补的像素了(全部修补完或者整块区域都不透明)
+            if filled.is_empty() {
+                break;
+            }
+            // 同一轮修补的像素互不参考，保证结果与遍历顺序无关
+            for (row, col, pixel) in filled {
+                rows[row][col * channels..(col + 1) * channels].copy_from_slice(&pixel[..channels]);
+                unknown[row * rect_width + col] = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use crate::types::InversionMode;
+    use crate::watermark::synthetic::{blend, max_difference, remove_in_place};
+    use crate::watermark::{ChromaSubsampling, ModelOptions, Page, WatermarkModel};
+
+    #[test]
+    fn nearly_opaque_pixels_are_inpainted_from_neighbours() -> anyhow::Result<()> {
+        // 水印覆盖(40, 30)到(57, 41)，中间(46, 34)到(51, 37)几乎不透明
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (46..=51, 34..=37) => 0.02,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let original = RgbImage::from_pixel(64, 48, Rgb([120, 130, 140]));
+        let watermarked = blend(&original, alpha, color);
+
+        let mut results = vec![];
+        for inpaint_alpha_threshold in [0.0, 0.1] {
+            let options = ModelOptions {
+                inpaint_alpha_threshold,
+                inversion_mode: InversionMode::Rgb,
+                deblocking: false,
+            };
+            let model = WatermarkModel::new(&black, &white, options)?;
+            let inpainted = model
+                .inpaint_mask
+                .iter()
+                .filter(|&&inpaint| inpaint)
+                .count();
+            let mut page = Page::Rgb(watermarked.clone(), ChromaSubsampling::NONE);
+            remove_in_place(&model, &mut page)?;
+            results.push((inpainted, max_difference(page.as_raw(), original.as_raw())));
+        }
+        // 不修补时，反推把量化误差放大了约50倍
+        let (inpainted, difference) = results[0];
+        assert_eq!(inpainted, 0);
+        assert!(difference > 1);
+        // 只修补几乎不透明的6x4个像素，它们被周围已经反推好的像素填满
+        let (inpainted, difference) = results[1];
+        assert_eq!(inpainted, 6 * 4);
+        assert!(difference <= 1);
+        Ok(())
+    }
+}
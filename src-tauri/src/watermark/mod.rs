@@ -0,0 +1,16 @@
+pub use align::Alignment;
+pub use detect::{best_removal, WatermarkRemoval};
+pub use model::{ModelOptions, WatermarkModel, MIN_LEVEL_DIFFERENCE};
+pub use page::{ChromaSubsampling, Page};
+
+mod align;
+mod deblock;
+mod detect;
+mod inpaint;
+mod model;
+mod page;
+mod resample;
+/// 测试用的合成图片和水印
+#[cfg(test)]
+mod synthetic;
+mod ycbcr;
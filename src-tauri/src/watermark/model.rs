@@ -0,0 +1,676 @@
+use anyhow::anyhow;
+use image::RgbImage;
+
+use crate::types::{InversionMode, RectData};
+use crate::watermark::ycbcr::LUMA_WEIGHTS;
+use crate::watermark::{detect, ChromaSubsampling, Page};
+
+/// alpha的最小值，避免白色背景与黑色背景的像素值相同时出现除以0
+pub(super) const MIN_ALPHA: f32 = 1.0 / 255.0;
+/// 背景水印图的像素值与背景颜色的差距不超过容差时，认为该像素没有被水印覆盖，这是容差的最小值
+const MIN_MASK_TOLERANCE: f32 = 1.0;
+/// 容差为背景水印图的平均噪点的这么多倍，平均后的背景水印图仍然带有JPEG的噪点，不能把噪点当成水印
+const MASK_NOISE_MULTIPLIER: f32 = 4.0;
+/// 两张背景水印图的背景颜色在每个通道上至少要相差这么多，才能准确地解出alpha
+pub const MIN_LEVEL_DIFFERENCE: u8 = 50;
+
+/// 根据两张纯色背景水印图预先计算好的去水印模型，同一尺寸的图片共用一个模型
+///
+/// 水印的叠加公式为 `out = in * alpha + watermark * (1 - alpha)`
+/// 两张背景水印图的背景颜色`level`已知且不同，所以可以解出每个像素每个通道的alpha
+/// 再变形得到 `in = out * gain + offset`，`gain`和`offset`只与背景水印图有关
+/// 所以只需要在构建模型时计算一次，去水印时每个像素的每个通道只剩一次乘法和一次加法
+///
+/// 水印只占图片右下角的一小块区域，所以模型只保存水印外接矩形`rect`内的数据，去水印时也只处理这个区域  
+/// 用背景水印图构建的模型有RGB三个通道，去除灰度图片的水印时用`to_luma`转换为单通道的模型
+#[derive(Clone)]
+pub struct WatermarkModel {
+    width: u32,
+    height: u32,
+    /// 水印的外接矩形，包含边界
+    rect: RectData,
+    /// 每个像素的通道数量，RGB模型为3，灰度模型为1
+    channels: usize,
+    /// `rect`内每个像素是否被水印覆盖，按行排列
+    pub(super) mask: Vec<bool>,
+    /// `rect`内每个像素每个通道的`1 / alpha`，排列方式与图片的数据一致
+    pub(super) gain: Vec<f32>,
+    /// `rect`内每个像素每个通道的偏移量，排列方式与图片的数据一致
+    pub(super) offset: Vec<f32>,
+    /// `rect`内每个像素在水平和垂直方向上的水印边缘权重，用于检测图片上是否有水印
+    pub(super) edge_weights: Vec<[f32; 2]>,
+    /// `rect`内每个像素是否需要修补，按行排列
+    ///
+    /// 水印接近不透明的像素alpha很小，反推时JPEG的噪点会被放大很多倍，留下亮斑或暗斑，所以改为用周围的像素修补
+    pub(super) inpaint_mask: Vec<bool>,
+    /// 构建模型时使用的选项，重新采样模型时需要用它重新计算`inpaint_mask`
+    options: ModelOptions,
+}
+
+/// 构建模型时的选项
+#[derive(Debug, Clone, Copy)]
+pub struct ModelOptions {
+    /// 有任意一个通道的alpha小于这个值的像素不做反推，而是用周围的像素修补，为0时不修补
+    pub inpaint_alpha_threshold: f32,
+    /// 反推原图时使用的颜色空间
+    pub inversion_mode: InversionMode,
+    /// 是否对去过水印的区域去块和降噪
+    pub deblocking: bool,
+}
+
+impl WatermarkModel {
+    /// 用两张背景颜色不同的背景水印图构建模型，两张图的顺序不影响结果
+    ///
+    /// 背景颜色不要求是纯黑和纯白，只要每个通道的差距都不小于`MIN_LEVEL_DIFFERENCE`即可
+    pub fn new(first: &RgbImage, second: &RgbImage, options: ModelOptions) -> anyhow::Result<Self> {
+        if first.dimensions() != second.dimensions() {
+            return Err(anyhow!(
+                "两张背景水印图的尺寸不一致，分别是 ({}x{}) 和 ({}x{})",
+                first.width(),
+                first.height(),
+                second.width(),
+                second.height(),
+            ));
+        }
+        // 图片边缘没有被水印覆盖，用边缘的颜色作为背景颜色
+        let first_level = background_level(first);
+        let second_level = background_level(second);
+        for i in 0..3 {
+            let difference = (first_level[i] - second_level[i]).abs();
+            if difference < f32::from(MIN_LEVEL_DIFFERENCE) {
+                return Err(anyhow!(
+                    "两张背景水印图的背景颜色过于接近，分别是 {first_level:?} 和 {second_level:?}，每个通道至少要相差{MIN_LEVEL_DIFFERENCE}"
+                ));
+            }
+        }
+        // 只要有一个通道的颜色与背景颜色的差距超过噪点的容差，就认为该像素被水印覆盖
+        let tolerance = mask_tolerance(first, second, first_level, second_level);
+        let is_watermarked = |x: u32, y: u32| {
+            let first_out = first.get_pixel(x, y).0.map(f32::from);
+            let second_out = second.get_pixel(x, y).0.map(f32::from);
+            (0..3).any(|i| {
+                let level_difference = second_level[i] - first_level[i];
+                (first_out[i] - first_level[i]).abs() > tolerance
+                    || (second_out[i] - first_out[i] - level_difference).abs() > tolerance
+            })
+        };
+        let rect = watermark_rect(first.width(), first.height(), is_watermarked)
+            .ok_or(anyhow!("背景水印图中没有水印"))?;
+
+        let len = ((rect.right - rect.left + 1) * (rect.bottom - rect.top + 1)) as usize;
+        let mut mask = Vec::with_capacity(len);
+        let mut gain = Vec::with_capacity(len * 3);
+        let mut offset = Vec::with_capacity(len * 3);
+        for y in rect.top..=rect.bottom {
+            for x in rect.left..=rect.right {
+                let watermarked = is_watermarked(x, y);
+                mask.push(watermarked);
+                let first_out = first.get_pixel(x, y).0.map(f32::from);
+                let second_out = second.get_pixel(x, y).0.map(f32::from);
+                for i in 0..3 {
+                    if !watermarked {
+                        // 没有被水印覆盖的像素不参与计算，令 in = out * 1 + 0 只是为了占位
+                        gain.push(1.0);
+                        offset.push(0.0);
+                        continue;
+                    }
+                    // alpha = (second_out - first_out) / (second_level - first_level)
+                    // 水印完全不透明时alpha为0，此时原图的信息已经丢失，只能用MIN_ALPHA近似
+                    let alpha = ((second_out[i] - first_out[i])
+                        / (second_level[i] - first_level[i]))
+                        .max(MIN_ALPHA);
+                    // in = (out - first_out) / alpha + first_level = out * (1 / alpha) + (first_level - first_out / alpha)
+                    gain.push(1.0 / alpha);
+                    offset.push(first_level[i] - first_out[i] / alpha);
+                }
+            }
+        }
+
+        Ok(Self::from_planes(
+            (first.width(), first.height()),
+            rect,
+            mask,
+            gain,
+            offset,
+            options,
+        ))
+    }
+
+    /// 用已经算好的`rect`内的数据构建模型，并计算出检测和修补需要的数据，通道数量由`gain`的长度决定
+    pub(super) fn from_planes(
+        (width, height): (u32, u32),
+        rect: RectData,
+        mask: Vec<bool>,
+        gain: Vec<f32>,
+        offset: Vec<f32>,
+        options: ModelOptions,
+    ) -> Self {
+        let rect_width = (rect.right - rect.left + 1) as usize;
+        let channels = gain.len() / mask.len();
+        let edge_weights = detect::edge_weights(rect_width, channels, &gain);
+        // alpha < threshold 等价于 gain * threshold > 1
+        let inpaint_mask = mask
+            .iter()
+            .zip(gain.chunks_exact(channels))
+            .map(|(&watermarked, gain)| {
+                watermarked
+                    && gain
+                        .iter()
+                        .any(|gain| gain * options.inpaint_alpha_threshold > 1.0)
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            rect,
+            channels,
+            mask,
+            gain,
+            offset,
+            edge_weights,
+            inpaint_mask,
+            options,
+        }
+    }
+
+    /// 转换为去除灰度图片水印的单通道模型，已经是单通道时原样复制
+    ///
+    /// 灰度图片的像素值就是亮度，所以按照亮度的转换系数加权合并各通道的alpha和水印的贡献`-offset * alpha`
+    pub fn to_luma(&self) -> Self {
+        if self.channels == 1 {
+            return self.clone();
+        }
+        let mut gain = Vec::with_capacity(self.mask.len());
+        let mut offset = Vec::with_capacity(self.mask.len());
+        let pixels = self.gain.chunks_exact(3).zip(self.offset.chunks_exact(3));
+        for (pixel_gain, pixel_offset) in pixels {
+            let mut alpha = 0.0;
+            let mut contribution = 0.0;
+            for i in 0..3 {
+                let channel_alpha = 1.0 / pixel_gain[i];
+                alpha += LUMA_WEIGHTS[i] * channel_alpha;
+                contribution += LUMA_WEIGHTS[i] * -pixel_offset[i] * channel_alpha;
+            }
+            let luma_gain = 1.0 / alpha.max(MIN_ALPHA);
+            gain.push(luma_gain);
+            offset.push(-contribution * luma_gain);
+        }
+        Self::from_planes(
+            self.dimensions(),
+            self.rect,
+            self.mask.clone(),
+            gain,
+            offset,
+            self.options,
+        )
+    }
+
+    pub(super) fn options(&self) -> ModelOptions {
+        self.options
+    }
+
+    /// 每个像素的通道数量，RGB模型为3，灰度模型为1
+    pub(super) fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// 把模型移植到尺寸为`width`x`height`的图片上，水印到图片右下角的距离保持不变，水印超出图片范围时返回`None`
+    ///
+    /// 水印固定在图片的右下角，所以同一种水印在不同尺寸的图片上只是整体平移
+    pub fn anchored_to(&self, width: u32, height: u32) -> Option<Self> {
+        let right_margin = self.width - 1 - self.rect.right;
+        let bottom_margin = self.height - 1 - self.rect.bottom;
+        let right = width.checked_sub(right_margin + 1)?;
+        let bottom = height.checked_sub(bottom_margin + 1)?;
+        let rect = RectData {
+            left: right.checked_sub(self.rect.right - self.rect.left)?,
+            top: bottom.checked_sub(self.rect.bottom - self.rect.top)?,
+            right,
+            bottom,
+        };
+        Some(Self {
+            width,
+            height,
+            rect,
+            ..self.clone()
+        })
+    }
+
+    /// 水印的外接矩形，包含边界
+    pub fn rect(&self) -> &RectData {
+        &self.rect
+    }
+
+    pub(super) fn rect_width(&self) -> usize {
+        (self.rect.right - self.rect.left + 1) as usize
+    }
+
+    /// 把水印外接矩形平移`(dx, dy)`，平移后超出图片范围时返回`None`
+    pub(super) fn shifted_rect(&self, (dx, dy): (i32, i32)) -> Option<RectData> {
+        let rect = RectData {
+            left: self.rect.left.checked_add_signed(dx)?,
+            top: self.rect.top.checked_add_signed(dy)?,
+            right: self.rect.right.checked_add_signed(dx)?,
+            bottom: self.rect.bottom.checked_add_signed(dy)?,
+        };
+        if rect.right >= self.width || rect.bottom >= self.height {
+            return None;
+        }
+        Some(rect)
+    }
+
+    /// 按行遍历`page`在矩形`rect`内的数据，每行包含`rect_width() * channels()`个通道
+    ///
+    /// `rect`必须是水印外接矩形或者由`shifted_rect`平移得到的矩形，`page`的通道数量必须与模型一致
+    pub(super) fn rect_rows<'a>(
+        &self,
+        page: &'a Page,
+        rect: &RectData,
+    ) -> impl Iterator<Item = &'a [u8]> {
+        let row_len = self.width as usize * self.channels;
+        let start = rect.left as usize * self.channels;
+        let end = start + self.rect_width() * self.channels;
+        page.as_raw()
+            .chunks_exact(row_len)
+            .skip(rect.top as usize)
+            .take((rect.bottom - rect.top + 1) as usize)
+            .map(move |row| &row[start..end])
+    }
+
+    /// 与`rect_rows`相同，但返回可变引用
+    pub(super) fn rect_rows_mut<'a>(
+        &self,
+        page: &'a mut Page,
+        rect: &RectData,
+    ) -> impl Iterator<Item = &'a mut [u8]> {
+        let row_len = self.width as usize * self.channels;
+        let start = rect.left as usize * self.channels;
+        let end = start + self.rect_width() * self.channels;
+        page.as_raw_mut()
+            .chunks_exact_mut(row_len)
+            .skip(rect.top as usize)
+            .take((rect.bottom - rect.top + 1) as usize)
+            .map(move |row| &mut row[start..end])
+    }
+
+    /// 水印覆盖的像素数量
+    pub(super) fn watermarked_count(&self) -> usize {
+        self.mask.iter().filter(|&&watermarked| watermarked).count()
+    }
+
+    /// 去除水印外接矩形内每行数据`rows`的水印，`rows`与`rect_rows`在`rect`处返回的数据一一对应
+    ///
+    /// 按照构建模型时选择的颜色空间反推，灰度模型只有亮度，总是逐像素反推  
+    /// 在YCbCr空间中反推时，色度按照原图的色度采样块`subsampling`反推  
+    /// 返回计算结果明显超出`[0, 255]`而被截断的通道数量  
+    /// 图片本身的量化误差会被`gain`放大，所以超出范围不到一个`gain`的通道不计入，需要修补的像素也不计入
+    pub(super) fn invert_rows(
+        &self,
+        rows: &mut [Vec<u8>],
+        rect: &RectData,
+        subsampling: ChromaSubsampling,
+    ) -> usize {
+        match self.options.inversion_mode {
+            InversionMode::YCbCr if self.channels == 3 => {
+                self.invert_rows_ycbcr(rows, rect, subsampling)
+            }
+            _ => self.invert_rows_per_channel(rows),
+        }
+    }
+
+    /// 逐像素逐通道去除`rows`的水印
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn invert_rows_per_channel(&self, rows: &mut [Vec<u8>]) -> usize {
+        let rect_width = self.rect_width();
+        let channels = self.channels;
+        let mut clipped_count = 0;
+        for (row, img_row) in rows.iter_mut().enumerate() {
+            let plane_row = row * rect_width * channels..(row + 1) * rect_width * channels;
+            let mask_row = &self.mask[row * rect_width..(row + 1) * rect_width];
+            let inpaint_row = &self.inpaint_mask[row * rect_width..(row + 1) * rect_width];
+            let gain_row = &self.gain[plane_row.clone()];
+            let offset_row = &self.offset[plane_row];
+
+            let pixels = img_row
+                .chunks_exact_mut(channels)
+                .zip(mask_row.iter().zip(inpaint_row))
+                .zip(
+                    gain_row
+                        .chunks_exact(channels)
+                        .zip(offset_row.chunks_exact(channels)),
+                );
+            for ((pixel, (&watermarked, &inpaint)), (gain, offset)) in pixels {
+                // 没有被水印覆盖的像素原样保留
+                if !watermarked {
+                    continue;
+                }
+                for i in 0..channels {
+                    // 加0.5后截断等价于四舍五入，将f32转换为u8自带clamp功能
+                    let value = f32::from(pixel[i]) * gain[i] + offset[i] + 0.5;
+                    if !inpaint && (value < -gain[i] || value > 256.0 + gain[i]) {
+                        clipped_count += 1;
+                    }
+                    pixel[i] = value as u8;
+                }
+            }
+        }
+        clipped_count
+    }
+}
+
+/// 计算所有满足`is_watermarked`的像素的外接矩形，没有满足条件的像素时返回`None`
+fn watermark_rect(
+    width: u32,
+    height: u32,
+    is_watermarked: impl Fn(u32, u32) -> bool,
+) -> Option<RectData> {
+    let mut rect: Option<RectData> = None;
+    for y in 0..height {
+        for x in 0..width {
+            if !is_watermarked(x, y) {
+                continue;
+            }
+            let rect = rect.get_or_insert(RectData {
+                left: x,
+                top: y,
+                right: x,
+                bottom: y,
+            });
+            rect.left = rect.left.min(x);
+            rect.right = rect.right.max(x);
+            rect.bottom = y;
+        }
+    }
+    rect
+}
+
+/// 根据两张背景水印图`first`和`second`的噪点计算判断像素是否被水印覆盖的容差
+///
+/// 两张图的背景颜色分别为`first_level`和`second_level`，记`excess = second_out - first_out - (second_level - first_level)`  
+/// 没有被水印覆盖的像素上`excess`只有噪点，正负各占一半；被水印覆盖的像素上`excess = (alpha - 1) * (second_level - first_level)`，总是与背景颜色之差反号  
+/// 所以只用与背景颜色之差同号的`excess`估计噪点，水印再多也不会混进来
+#[allow(clippy::cast_precision_loss)]
+fn mask_tolerance(
+    first: &RgbImage,
+    second: &RgbImage,
+    first_level: [f32; 3],
+    second_level: [f32; 3],
+) -> f32 {
+    let mut sums = [0.0_f32; 3];
+    let mut counts = [0_u32; 3];
+    for (first_out, second_out) in first.pixels().zip(second.pixels()) {
+        for i in 0..3 {
+            let level_difference = second_level[i] - first_level[i];
+            let excess = (f32::from(second_out[i]) - f32::from(first_out[i]) - level_difference)
+                * level_difference.signum();
+            if excess > 0.0 {
+                sums[i] += excess;
+                counts[i] += 1;
+            }
+        }
+    }
+    let noise = (0..3)
+        .filter(|&i| counts[i] > 0)
+        .map(|i| sums[i] / counts[i] as f32)
+        .fold(0.0, f32::max);
+    (noise * MASK_NOISE_MULTIPLIER).max(MIN_MASK_TOLERANCE)
+}
+
+/// 计算图片`img`边缘像素每个通道的中位数，作为背景颜色
+#[allow(clippy::cast_precision_loss)]
+fn background_level(img: &RgbImage) -> [f32; 3] {
+    let (width, height) = img.dimensions();
+    let mut histograms = [[0_u32; 256]; 3];
+    let mut count = 0;
+    let border = (0..width)
+        .flat_map(|x| [(x, 0), (x, height - 1)])
+        .chain((0..height).flat_map(|y| [(0, y), (width - 1, y)]));
+    for (x, y) in border {
+        let pixel = img.get_pixel(x, y);
+        for (histogram, &value) in histograms.iter_mut().zip(pixel.0.iter()) {
+            histogram[value as usize] += 1;
+        }
+        count += 1;
+    }
+    histograms.map(|histogram| {
+        let mut accumulated = 0;
+        let median = histogram.iter().position(|&n| {
+            accumulated += n;
+            accumulated * 2 >= count
+        });
+        median.map_or(0.0, |value| value as f32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use image::{GrayImage, Luma, Rgb};
+
+    use super::*;
+    use crate::watermark::synthetic::{
+        blend, gradient, max_difference, plain_options, remove_in_place,
+    };
+
+    #[test]
+    fn removal_inverts_known_alpha_blend() -> anyhow::Result<()> {
+        // 水印覆盖(40, 30)到(57, 41)，alpha每隔两列在0.6和0.8之间交替
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let model = WatermarkModel::new(&black, &white, plain_options(InversionMode::Rgb))?;
+
+        let original = gradient(64, 48);
+        let mut page = Page::Rgb(blend(&original, alpha, color), ChromaSubsampling::NONE);
+        remove_in_place(&model, &mut page)?;
+        assert!(max_difference(page.as_raw(), original.as_raw()) <= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn unmasked_pixels_in_rect_are_untouched() -> anyhow::Result<()> {
+        // 两块水印之间隔着6列没有水印的像素，它们在外接矩形内但不在掩码内
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=45 | 52..=57, 30..=41) => 0.7,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let model = WatermarkModel::new(&black, &white, plain_options(InversionMode::Rgb))?;
+        let rect = model.rect();
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (40, 30, 57, 41)
+        );
+        assert_eq!(model.watermarked_count(), 12 * 12);
+
+        let original = gradient(64, 48);
+        let watermarked = blend(&original, alpha, color);
+        let mut page = Page::Rgb(watermarked.clone(), ChromaSubsampling::NONE);
+        remove_in_place(&model, &mut page)?;
+        let Page::Rgb(img, _) = page else {
+            unreachable!()
+        };
+        for (x, y, pixel) in img.enumerate_pixels() {
+            if alpha(x, y) < 1.0 {
+                assert!(max_difference(&pixel.0, &original.get_pixel(x, y).0) <= 1);
+            } else {
+                assert_eq!(pixel, watermarked.get_pixel(x, y), "({x}, {y})");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_possible_wrap)]
+    #[allow(clippy::cast_sign_loss)]
+    fn noise_on_backgrounds_is_not_masked() -> anyhow::Result<()> {
+        // 每个像素每个通道都带有[-3, 3]的噪点，两张图的噪点不同
+        let noisy = |level: u8, seed: u32| {
+            RgbImage::from_fn(64, 48, |x, y| {
+                Rgb([0, 1, 2].map(|i| {
+                    let noise = ((x * 7 + y * 13 + i * 5 + seed) % 7) as i32 - 3;
+                    (i32::from(level) + noise) as u8
+                }))
+            })
+        };
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) => 0.7,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let first = blend(&noisy(20, 0), alpha, color);
+        let second = blend(&noisy(235, 3), alpha, color);
+        let model = WatermarkModel::new(&first, &second, plain_options(InversionMode::Rgb))?;
+        let rect = model.rect();
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (40, 30, 57, 41)
+        );
+        assert_eq!(model.watermarked_count(), 18 * 12);
+        Ok(())
+    }
+
+    #[test]
+    fn solves_any_two_distinct_backgrounds_in_either_order() -> anyhow::Result<()> {
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        // 彩色的背景颜色，每个通道相差都不小于MIN_LEVEL_DIFFERENCE
+        let dark = blend(
+            &RgbImage::from_pixel(64, 48, Rgb([60, 30, 90])),
+            alpha,
+            color,
+        );
+        let light = blend(
+            &RgbImage::from_pixel(64, 48, Rgb([200, 140, 170])),
+            alpha,
+            color,
+        );
+        let options = plain_options(InversionMode::Rgb);
+        let model = WatermarkModel::new(&dark, &light, options)?;
+        let swapped = WatermarkModel::new(&light, &dark, options)?;
+        for (a, b) in model.gain.iter().zip(&swapped.gain) {
+            assert!((a - b).abs() < 1e-3);
+        }
+        for (a, b) in model.offset.iter().zip(&swapped.offset) {
+            assert!((a - b).abs() < 1e-2);
+        }
+
+        let original = gradient(64, 48);
+        let mut page = Page::Rgb(blend(&original, alpha, color), ChromaSubsampling::NONE);
+        remove_in_place(&swapped, &mut page)?;
+        assert!(max_difference(page.as_raw(), original.as_raw()) <= 1);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_backgrounds_that_cannot_be_solved() {
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) => 0.7,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let options = plain_options(InversionMode::Rgb);
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([20; 3])), alpha, color);
+        // 尺寸不一致
+        let narrow = blend(&RgbImage::from_pixel(63, 48, Rgb([235; 3])), alpha, color);
+        assert!(WatermarkModel::new(&black, &narrow, options).is_err());
+        // 蓝色通道只相差40，小于MIN_LEVEL_DIFFERENCE
+        let close = blend(
+            &RgbImage::from_pixel(64, 48, Rgb([235, 235, 60])),
+            alpha,
+            color,
+        );
+        assert!(WatermarkModel::new(&black, &close, options).is_err());
+        // 没有水印的纯色图片
+        let plain_black = RgbImage::from_pixel(64, 48, Rgb([20; 3]));
+        let plain_white = RgbImage::from_pixel(64, 48, Rgb([235; 3]));
+        assert!(WatermarkModel::new(&plain_black, &plain_white, options).is_err());
+    }
+
+    #[test]
+    fn anchored_model_keeps_bottom_right_margins() -> anyhow::Result<()> {
+        // 水印覆盖(40, 30)到(57, 41)，距离64x48图片的右边和下边都是6个像素
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let model = WatermarkModel::new(&black, &white, plain_options(InversionMode::Rgb))?;
+
+        let anchored = model.anchored_to(80, 60).context("移植失败")?;
+        let rect = anchored.rect();
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (56, 42, 73, 53)
+        );
+        // 80x60的图片上的水印同样距离右边和下边6个像素
+        let moved = |x: u32, y: u32| {
+            if x >= 16 && y >= 12 {
+                alpha(x - 16, y - 12)
+            } else {
+                1.0
+            }
+        };
+        let original = gradient(80, 60);
+        let mut page = Page::Rgb(blend(&original, moved, color), ChromaSubsampling::NONE);
+        remove_in_place(&anchored, &mut page)?;
+        assert!(max_difference(page.as_raw(), original.as_raw()) <= 1);
+        // 图片太窄或太矮，放不下水印
+        assert!(model.anchored_to(20, 60).is_none());
+        assert!(model.anchored_to(80, 10).is_none());
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn luma_model_removes_watermark_from_gray_page() -> anyhow::Result<()> {
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let model = WatermarkModel::new(&black, &white, plain_options(InversionMode::Rgb))?;
+        let luma_model = model.to_luma();
+        assert_eq!(luma_model.channels(), 1);
+
+        // 灰度图片上的彩色水印按照亮度的转换系数变成灰色
+        let to_gray = |img: &RgbImage| {
+            GrayImage::from_fn(img.width(), img.height(), |x, y| {
+                let pixel = img.get_pixel(x, y).0;
+                let luma: f32 = (0..3).map(|i| LUMA_WEIGHTS[i] * f32::from(pixel[i])).sum();
+                Luma([(luma + 0.5) as u8])
+            })
+        };
+        let original = GrayImage::from_fn(64, 48, |x, y| Luma([(40 + x + y * 2) as u8]));
+        let gray_rgb = RgbImage::from_fn(64, 48, |x, y| Rgb([original.get_pixel(x, y).0[0]; 3]));
+        let mut page = Page::Luma(to_gray(&blend(&gray_rgb, alpha, color)));
+        remove_in_place(&luma_model, &mut page)?;
+        assert!(max_difference(page.as_raw(), original.as_raw()) <= 1);
+        Ok(())
+    }
+}
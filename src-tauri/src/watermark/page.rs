@@ -0,0 +1,105 @@
+use image::{imageops, DynamicImage, GrayImage, RgbImage};
+
+/// 要去水印的一页漫画
+///
+/// 大部分漫画页都是灰度的JPEG，解码成RGB后三个通道完全相同，所以灰度图片保持单通道，去水印和保存都少处理2/3的数据  
+/// 不包含alpha通道，去水印不需要处理alpha通道，由调用者单独保存  
+/// 彩色图片同时记录原图的色度采样块，在YCbCr空间中去水印时需要按照采样块反推色度
+pub enum Page {
+    Rgb(RgbImage, ChromaSubsampling),
+    Luma(GrayImage),
+}
+
+/// 色度采样块的尺寸，块内的像素共用一组色度
+///
+/// 原图不是JPEG或者JPEG没有对色度降采样时为1x1，比如4:2:0采样的JPEG为2x2
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChromaSubsampling {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ChromaSubsampling {
+    /// 没有对色度降采样
+    pub const NONE: ChromaSubsampling = ChromaSubsampling {
+        width: 1,
+        height: 1,
+    };
+}
+
+impl Page {
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            Page::Rgb(img, _) => img.dimensions(),
+            Page::Luma(img) => img.dimensions(),
+        }
+    }
+
+    /// 每个像素的通道数量
+    pub fn channels(&self) -> usize {
+        match self {
+            Page::Rgb(..) => 3,
+            Page::Luma(_) => 1,
+        }
+    }
+
+    pub fn is_luma(&self) -> bool {
+        matches!(self, Page::Luma(_))
+    }
+
+    /// 原图的色度采样块，灰度图片没有色度，为1x1
+    pub fn chroma_subsampling(&self) -> ChromaSubsampling {
+        match self {
+            Page::Rgb(_, subsampling) => *subsampling,
+            Page::Luma(_) => ChromaSubsampling::NONE,
+        }
+    }
+
+    /// 从第`top`行开始截取`height`行，得到新的图片
+    ///
+    /// `top`不在色度采样块的边界上时，新图片的采样块与原图对不齐，所以不再记录原图的色度采样块
+    pub fn crop_rows(&self, top: u32, height: u32) -> Page {
+        match self {
+            Page::Rgb(img, subsampling) => {
+                let img = imageops::crop_imm(img, 0, top, img.width(), height).to_image();
+                if top.is_multiple_of(subsampling.height) {
+                    Page::Rgb(img, *subsampling)
+                } else {
+                    Page::Rgb(img, ChromaSubsampling::NONE)
+                }
+            }
+            Page::Luma(img) => {
+                Page::Luma(imageops::crop_imm(img, 0, top, img.width(), height).to_image())
+            }
+        }
+    }
+
+    /// 图片的原始数据，按行排列
+    pub fn as_raw(&self) -> &[u8] {
+        match self {
+            Page::Rgb(img, _) => img.as_raw(),
+            Page::Luma(img) => img.as_raw(),
+        }
+    }
+
+    /// 与`as_raw`相同，但返回可变引用
+    pub(super) fn as_raw_mut(&mut self) -> &mut [u8] {
+        match self {
+            Page::Rgb(img, _) => img,
+            Page::Luma(img) => img,
+        }
+    }
+}
+
+impl From<DynamicImage> for Page {
+    /// 解码后的图片不知道原图的色度采样块，彩色图片的采样块为1x1
+    fn from(img: DynamicImage) -> Self {
+        match img {
+            DynamicImage::ImageLuma8(img) => Page::Luma(img),
+            img @ (DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)) => Page::Luma(img.to_luma8()),
+            img => Page::Rgb(img.to_rgb8(), ChromaSubsampling::NONE),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::types::RectData;
+use crate::watermark::model::MIN_ALPHA;
+use crate::watermark::WatermarkModel;
+
+impl WatermarkModel {
+    /// 重新采样模型，得到尺寸为`dimensions`的图片上、水印外接矩形为`rect`的新模型
+    ///
+    /// `source`把新模型中的坐标映射为原模型中的坐标，像素中心的坐标为整数  
+    /// 每个像素在每个方向上均匀取`samples`个采样点，每个采样点用双线性插值，再取平均，缩小时用于抗锯齿  
+    /// 插值的对象是alpha和水印的贡献`-offset * alpha`，而不是`gain`和`offset`，因为前两者才随位置线性变化
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_sign_loss)]
+    pub(super) fn resampled(
+        &self,
+        dimensions: (u32, u32),
+        rect: RectData,
+        samples: u32,
+        source: impl Fn(f32, f32) -> (f32, f32),
+    ) -> Self {
+        let old_rect = *self.rect();
+        let old_rect_width = self.rect_width();
+        let channels = self.channels();
+        // 返回原模型在(x, y)处的(是否被水印覆盖, 每个通道的alpha, 每个通道水印的贡献)，外接矩形以外的像素没有被水印覆盖
+        // 灰度模型只用到第一个通道
+        let pixel_at = |x: i64, y: i64| {
+            let inside = x >= i64::from(old_rect.left)
+                && x <= i64::from(old_rect.right)
+                && y >= i64::from(old_rect.top)
+                && y <= i64::from(old_rect.bottom);
+            if !inside {
+                return (false, [1.0; 3], [0.0; 3]);
+            }
+            let index = (y - i64::from(old_rect.top)) as usize * old_rect_width
+                + (x - i64::from(old_rect.left)) as usize;
+            let mut alpha = [1.0; 3];
+            let mut contribution = [0.0; 3];
+            for i in 0..channels {
+                alpha[i] = 1.0 / self.gain[index * channels + i];
+                contribution[i] = -self.offset[index * channels + i] * alpha[i];
+            }
+            (self.mask[index], alpha, contribution)
+        };
+        // 双线性插值，只要有一个参与插值的像素被水印覆盖，就认为该位置被水印覆盖
+        let bilinear = |x: f32, y: f32| {
+            let (x0, y0) = (x.floor(), y.floor());
+            let (tx, ty) = (x - x0, y - y0);
+            let corners = [
+                (0, 0, (1.0 - tx) * (1.0 - ty)),
+                (1, 0, tx * (1.0 - ty)),
+                (0, 1, (1.0 - tx) * ty),
+                (1, 1, tx * ty),
+            ];
+            let mut watermarked = false;
+            let mut alpha = [0.0; 3];
+            let mut contribution = [0.0; 3];
+            for (cx, cy, weight) in corners {
+                if weight <= 0.0 {
+                    continue;
+                }
+                let (corner_watermarked, corner_alpha, corner_contribution) =
+                    pixel_at(x0 as i64 + cx, y0 as i64 + cy);
+                watermarked |= corner_watermarked;
+                for i in 0..channels {
+                    alpha[i] += weight * corner_alpha[i];
+                    contribution[i] += weight * corner_contribution[i];
+                }
+            }
+            (watermarked, alpha, contribution)
+        };
+
+        let samples = samples.max(1);
+        let sample_offsets: Vec<f32> = (0..samples)
+            .map(|i| (i as f32 + 0.5) / samples as f32 - 0.5)
+            .collect();
+        let sample_count = (samples * samples) as f32;
+        let len = ((rect.right - rect.left + 1) * (rect.bottom - rect.top + 1)) as usize;
+        let mut mask = Vec::with_capacity(len);
+        let mut gain = Vec::with_capacity(len * channels);
+        let mut offset = Vec::with_capacity(len * channels);
+        for y in rect.top..=rect.bottom {
+            for x in rect.left..=rect.right {
+                let mut watermarked = false;
+                let mut alpha = [0.0; 3];
+                let mut contribution = [0.0; 3];
+                for &sample_y in &sample_offsets {
+                    for &sample_x in &sample_offsets {
+                        let (source_x, source_y) = source(x as f32 + sample_x, y as f32 + sample_y);
+                        let (sample_watermarked, sample_alpha, sample_contribution) =
+                            bilinear(source_x, source_y);
+                        watermarked |= sample_watermarked;
+                        for i in 0..channels {
+                            alpha[i] += sample_alpha[i] / sample_count;
+                            contribution[i] += sample_contribution[i] / sample_count;
+                        }
+                    }
+                }
+                mask.push(watermarked);
+                for i in 0..channels {
+                    if !watermarked {
+                        gain.push(1.0);
+                        offset.push(0.0);
+                        continue;
+                    }
+                    // in = (out - contribution) / alpha = out * gain - contribution * gain
+                    let pixel_gain = 1.0 / alpha[i].max(MIN_ALPHA);
+                    gain.push(pixel_gain);
+                    offset.push(-contribution[i] * pixel_gain);
+                }
+            }
+        }
+
+        Self::from_planes(dimensions, rect, mask, gain, offset, self.options())
+    }
+
+    /// 把模型缩放`scale`倍后移植到尺寸为`width`x`height`的图片上，水印到图片右下角的距离也同样缩放，水印超出图片范围时返回`None`
+    ///
+    /// 用于去除被缩小过的图片的水印，这些图片上的水印也被同样缩小了
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_precision_loss)]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn scaled(&self, scale: f32, width: u32, height: u32) -> Option<Self> {
+        let (old_width, old_height) = self.dimensions();
+        let old_rect = self.rect();
+        // 以图片右下角为原点，计算外接矩形的左上角和右下角到原点的距离，缩放后再换算回坐标
+        let scale_distance = |distance: u32| distance as f32 * scale;
+        let left_distance = scale_distance(old_width - old_rect.left).ceil() as u32;
+        let top_distance = scale_distance(old_height - old_rect.top).ceil() as u32;
+        let right_distance = scale_distance(old_width - 1 - old_rect.right).floor() as u32;
+        let bottom_distance = scale_distance(old_height - 1 - old_rect.bottom).floor() as u32;
+        let rect = RectData {
+            left: width.checked_sub(left_distance)?,
+            top: height.checked_sub(top_distance)?,
+            right: width.checked_sub(right_distance + 1)?,
+            bottom: height.checked_sub(bottom_distance + 1)?,
+        };
+        if rect.left > rect.right || rect.top > rect.bottom {
+            return None;
+        }
+        // 新图片中的像素中心到右(下)边缘的距离除以scale，就是原图片中对应位置到右(下)边缘的距离
+        let (width_f, height_f) = (width as f32, height as f32);
+        let (old_width_f, old_height_f) = (old_width as f32, old_height as f32);
+        let source = |x: f32, y: f32| {
+            let source_x = old_width_f - 0.5 - (width_f - 0.5 - x) / scale;
+            let source_y = old_height_f - 0.5 - (height_f - 0.5 - y) / scale;
+            (source_x, source_y)
+        };
+        // 缩小时每个新像素覆盖多个原像素，按照覆盖的范围增加采样点
+        let samples = (1.0 / scale).ceil().max(1.0) as u32;
+        Some(self.resampled((width, height), rect, samples, source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+    use image::{Rgb, RgbImage};
+
+    use crate::types::InversionMode;
+    use crate::watermark::synthetic::{
+        blend, gradient, max_difference, plain_options, remove_in_place,
+    };
+    use crate::watermark::{ChromaSubsampling, Page, WatermarkModel};
+
+    #[test]
+    fn scaled_model_removes_watermark_from_shrunk_page() -> anyhow::Result<()> {
+        // 水印覆盖(40, 30)到(57, 41)，距离64x48图片的右边和下边都是6个像素，alpha每隔两列交替
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let model = WatermarkModel::new(&black, &white, plain_options(InversionMode::Rgb))?;
+
+        let unchanged = model.scaled(1.0, 64, 48).context("缩放失败")?;
+        let rect = unchanged.rect();
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (40, 30, 57, 41)
+        );
+
+        let half = model.scaled(0.5, 32, 24).context("缩放失败")?;
+        let rect = half.rect();
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (20, 15, 28, 20)
+        );
+        assert_eq!(half.watermarked_count(), 54);
+        // 整张图片缩小一半后，水印也缩小一半，覆盖(20, 15)到(28, 20)，每个像素对应原本alpha相同的两列
+        let shrunk = |x: u32, y: u32| alpha(x * 2, y * 2);
+        let original = gradient(32, 24);
+        let mut page = Page::Rgb(blend(&original, shrunk, color), ChromaSubsampling::NONE);
+        remove_in_place(&half, &mut page)?;
+        assert!(max_difference(page.as_raw(), original.as_raw()) <= 1);
+
+        // 缩小后的图片放不下水印
+        assert!(model.scaled(0.5, 8, 8).is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use anyhow::Context;
+use image::{Rgb, RgbImage};
+
+use crate::types::InversionMode;
+use crate::watermark::{best_removal, Alignment, ModelOptions, Page, WatermarkModel};
+
+/// 把水印叠加到`img`上，`out = in * alpha + color * (1 - alpha)`，结果四舍五入
+///
+/// `alpha(x, y)`为`(x, y)`处的alpha，没有水印的地方为1
+#[allow(clippy::cast_possible_truncation)]
+#[allow(clippy::cast_sign_loss)]
+pub fn blend(img: &RgbImage, alpha: impl Fn(u32, u32) -> f32, color: [f32; 3]) -> RgbImage {
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let alpha = alpha(x, y);
+        let pixel = img.get_pixel(x, y).0;
+        Rgb([0, 1, 2]
+            .map(|i| (f32::from(pixel[i]) * alpha + color[i] * (1.0 - alpha)).round() as u8))
+    })
+}
+
+/// 平滑渐变的彩色漫画内容，相邻像素的差距很小，不会被误认为水印的边缘，尺寸不能超过80x60
+#[allow(clippy::cast_possible_truncation)]
+pub fn gradient(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        Rgb([
+            (40 + x + y) as u8,
+            (30 + y * 3) as u8,
+            (50 + x + y * 2) as u8,
+        ])
+    })
+}
+
+/// 不修补、不去块的模型选项
+pub fn plain_options(inversion_mode: InversionMode) -> ModelOptions {
+    ModelOptions {
+        inpaint_alpha_threshold: 0.0,
+        inversion_mode,
+        deblocking: false,
+    }
+}
+
+/// `a`和`b`每个通道的最大差距
+pub fn max_difference(a: &[u8], b: &[u8]) -> u8 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| a.abs_diff(*b))
+        .max()
+        .unwrap_or(0)
+}
+
+/// 不搜索平移量，用`model`去除`page`的水印，检测不到水印时返回错误
+pub fn remove_in_place(model: &WatermarkModel, page: &mut Page) -> anyhow::Result<()> {
+    let alignment = Alignment {
+        search_radius: 0,
+        subpixel: false,
+    };
+    best_removal(std::slice::from_ref(model), page, alignment)
+        .context("检测不到水印")?
+        .apply(page);
+    Ok(())
+}
@@ -0,0 +1,275 @@
+use crate::types::RectData;
+use crate::watermark::model::MIN_ALPHA;
+use crate::watermark::{ChromaSubsampling, WatermarkModel};
+
+/// JPEG(JFIF)使用的BT.601全范围转换系数，依次为R、G、B的权重
+pub(super) const LUMA_WEIGHTS: [f32; 3] = [0.299, 0.587, 0.114];
+const CB_WEIGHTS: [f32; 3] = [-0.168_736, -0.331_264, 0.5];
+const CR_WEIGHTS: [f32; 3] = [0.5, -0.418_688, -0.081_312];
+
+/// 一个色度采样块内所有像素的累加值
+#[derive(Default, Clone, Copy)]
+struct ChromaBlock {
+    /// 块内像素的数量
+    count: f32,
+    /// 块内是否有被水印覆盖的像素
+    watermarked: bool,
+    /// 块内像素去水印前的`[Cb, Cr]`之和
+    chroma: [f32; 2],
+    /// 块内像素各通道alpha平均值之和
+    alpha: f32,
+    /// 块内像素水印对`[Cb, Cr]`的贡献之和
+    contribution: [f32; 2],
+}
+
+impl ChromaBlock {
+    /// 反推块内色度的平均值后，每个像素的`[Cb, Cr]`需要加上的修正量
+    fn correction(&self) -> [f32; 2] {
+        let alpha = (self.alpha / self.count).max(MIN_ALPHA);
+        [0, 1].map(|i| {
+            // 去水印前色度的平均值为 out = in * alpha + contribution，以128为中心反推
+            let out = self.chroma[i] / self.count - 128.0;
+            let contribution = self.contribution[i] / self.count;
+            (out - contribution) / alpha - out
+        })
+    }
+}
+
+impl WatermarkModel {
+    /// 在YCbCr空间中去除`rows`的水印，`rect`是`rows`在图片上的位置，`subsampling`是原图的色度采样块，两者一起确定采样块的边界
+    ///
+    /// JPEG以全分辨率保存亮度，色度却只保存每个采样块的平均值，所以彩色图片上逐像素反推色度会在水印边缘留下彩边  
+    /// 这里亮度逐像素反推，色度按照采样块反推出平均值，再像解码时一样把修正量插值到被水印覆盖的像素上，没有被覆盖的像素保持不变  
+    /// 原图没有对色度降采样时，每个采样块只有一个像素，即逐像素反推色度  
+    /// 每个像素的alpha不是各通道相同时，按照转换系数加权得到亮度和色度各自的alpha，这时的结果是近似的
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    pub(super) fn invert_rows_ycbcr(
+        &self,
+        rows: &mut [Vec<u8>],
+        rect: &RectData,
+        subsampling: ChromaSubsampling,
+    ) -> usize {
+        let rect_width = self.rect_width();
+        let ChromaSubsampling {
+            width: block_width,
+            height: block_height,
+        } = subsampling;
+        // 采样块按照图片的绝对坐标划分，外接矩形的左上角不一定在块的边界上
+        let block_left = rect.left / block_width;
+        let block_top = rect.top / block_height;
+        let blocks_per_row = (rect.right / block_width - block_left + 1) as usize;
+        let block_rows = (rect.bottom / block_height - block_top + 1) as usize;
+        let block_index = |row: usize, col: usize| {
+            let block_row = (rect.top + row as u32) / block_height - block_top;
+            let block_col = (rect.left + col as u32) / block_width - block_left;
+            block_row as usize * blocks_per_row + block_col as usize
+        };
+
+        // 先逐像素反推亮度，同时累加每个块的色度
+        let mut blocks = vec![ChromaBlock::default(); blocks_per_row * block_rows];
+        let mut ycbcr_rows: Vec<Vec<[f32; 3]>> = Vec::with_capacity(rows.len());
+        for (row, img_row) in rows.iter().enumerate() {
+            let mut ycbcr_row = Vec::with_capacity(rect_width);
+            for (col, pixel) in img_row.chunks_exact(3).enumerate() {
+                let index = row * rect_width + col;
+                let rgb = [0, 1, 2].map(|i| f32::from(pixel[i]));
+                let [mut luma, cb, cr] = rgb_to_ycbcr(rgb);
+                let block = &mut blocks[block_index(row, col)];
+                block.count += 1.0;
+                block.chroma[0] += cb;
+                block.chroma[1] += cr;
+                if self.mask[index] {
+                    let gain = &self.gain[index * 3..index * 3 + 3];
+                    let offset = &self.offset[index * 3..index * 3 + 3];
+                    let alpha = [0, 1, 2].map(|i| 1.0 / gain[i]);
+                    let contribution = [0, 1, 2].map(|i| -offset[i] * alpha[i]);
+                    let luma_alpha = weighted_sum(LUMA_WEIGHTS, alpha).max(MIN_ALPHA);
+                    luma = (luma - weighted_sum(LUMA_WEIGHTS, contribution)) / luma_alpha;
+                    block.watermarked = true;
+                    block.alpha += alpha.iter().sum::<f32>() / 3.0;
+                    block.contribution[0] += weighted_sum(CB_WEIGHTS, contribution);
+                    block.contribution[1] += weighted_sum(CR_WEIGHTS, contribution);
+                } else {
+                    block.alpha += 1.0;
+                }
+                ycbcr_row.push([luma, cb, cr]);
+            }
+            ycbcr_rows.push(ycbcr_row);
+        }
+
+        // 再修正被水印覆盖的块的色度，转换回RGB
+        let corrections: Vec<Option<[f32; 2]>> = blocks
+            .iter()
+            .map(|block| block.watermarked.then(|| block.correction()))
+            .collect();
+        // 解码JPEG时色度是在相邻的块之间插值得到的，所以修正量也同样插值，没有被水印覆盖的块修正量为0
+        let correction_at = |row: usize, col: usize| {
+            let x = rect.left + col as u32;
+            let y = rect.top + row as u32;
+            let mut correction = [0.0; 2];
+            for (block_x, weight_x) in upsampling_taps(x, block_width) {
+                for (block_y, weight_y) in upsampling_taps(y, block_height) {
+                    let inside = (block_left..block_left + blocks_per_row as u32)
+                        .contains(&block_x)
+                        && (block_top..block_top + block_rows as u32).contains(&block_y);
+                    if !inside {
+                        continue;
+                    }
+                    let index = (block_y - block_top) as usize * blocks_per_row
+                        + (block_x - block_left) as usize;
+                    if let Some(block_correction) = corrections[index] {
+                        for i in 0..2 {
+                            correction[i] += weight_x * weight_y * block_correction[i];
+                        }
+                    }
+                }
+            }
+            correction
+        };
+        let mut clipped_count = 0;
+        for (row, (img_row, ycbcr_row)) in rows.iter_mut().zip(ycbcr_rows).enumerate() {
+            for (col, (pixel, [luma, cb, cr])) in
+                img_row.chunks_exact_mut(3).zip(ycbcr_row).enumerate()
+            {
+                let index = row * rect_width + col;
+                // 没有被水印覆盖的像素原样保留
+                if !self.mask[index] {
+                    continue;
+                }
+                let [cb_correction, cr_correction] = correction_at(row, col);
+                let gain = &self.gain[index * 3..index * 3 + 3];
+                let inpaint = self.inpaint_mask[index];
+                let rgb = ycbcr_to_rgb([luma, cb + cb_correction, cr + cr_correction]);
+                for i in 0..3 {
+                    // 加0.5后截断等价于四舍五入，将f32转换为u8自带clamp功能
+                    let value = rgb[i] + 0.5;
+                    if !inpaint && (value < -gain[i] || value > 256.0 + gain[i]) {
+                        clipped_count += 1;
+                    }
+                    pixel[i] = value as u8;
+                }
+            }
+        }
+        clipped_count
+    }
+}
+
+/// 像素坐标`x`在色度上采样时用到的两个块的坐标和权重，`block_size`是这个方向上采样块的边长，与libjpeg的上采样一致
+///
+/// 边长为2时使用fancy upsampling，每个块的中心位于两个像素之间，所以离像素近的块权重为3/4，另一个块权重为1/4  
+/// 其他边长直接复制块的色度
+fn upsampling_taps(x: u32, block_size: u32) -> [(u32, f32); 2] {
+    let block = x / block_size;
+    if block_size != 2 {
+        return [(block, 1.0), (block, 0.0)];
+    }
+    let neighbour = if x.is_multiple_of(block_size) {
+        block.saturating_sub(1)
+    } else {
+        block + 1
+    };
+    if neighbour == block {
+        return [(block, 1.0), (block, 0.0)];
+    }
+    [(block, 0.75), (neighbour, 0.25)]
+}
+
+fn weighted_sum(weights: [f32; 3], values: [f32; 3]) -> f32 {
+    weights.iter().zip(values).map(|(w, v)| w * v).sum()
+}
+
+fn rgb_to_ycbcr(rgb: [f32; 3]) -> [f32; 3] {
+    [
+        weighted_sum(LUMA_WEIGHTS, rgb),
+        128.0 + weighted_sum(CB_WEIGHTS, rgb),
+        128.0 + weighted_sum(CR_WEIGHTS, rgb),
+    ]
+}
+
+fn ycbcr_to_rgb([luma, cb, cr]: [f32; 3]) -> [f32; 3] {
+    let (cb, cr) = (cb - 128.0, cr - 128.0);
+    [
+        luma + 1.402 * cr,
+        luma - 0.344_136 * cb - 0.714_136 * cr,
+        luma + 1.772 * cb,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::types::InversionMode;
+    use crate::watermark::synthetic::{
+        blend, gradient, max_difference, plain_options, remove_in_place,
+    };
+    use crate::watermark::Page;
+
+    /// 模拟以4:2:0保存再解码的JPEG，每个2x2块的色度取平均值，再像libjpeg一样插值回每个像素
+    #[allow(clippy::cast_possible_truncation)]
+    #[allow(clippy::cast_sign_loss)]
+    fn subsample_chroma(img: &RgbImage) -> RgbImage {
+        let (width, height) = img.dimensions();
+        let (blocks_per_row, block_rows) = (width.div_ceil(2), height.div_ceil(2));
+        let ycbcr: Vec<[f32; 3]> = img
+            .pixels()
+            .map(|pixel| rgb_to_ycbcr(pixel.0.map(f32::from)))
+            .collect();
+        let mut blocks = vec![[0.0; 2]; (blocks_per_row * block_rows) as usize];
+        for (index, [_, cb, cr]) in ycbcr.iter().enumerate() {
+            let (x, y) = (index as u32 % width, index as u32 / width);
+            let block = &mut blocks[((y / 2) * blocks_per_row + x / 2) as usize];
+            block[0] += cb / 4.0;
+            block[1] += cr / 4.0;
+        }
+        RgbImage::from_fn(width, height, |x, y| {
+            let mut chroma = [0.0; 2];
+            for (block_x, weight_x) in upsampling_taps(x, 2) {
+                for (block_y, weight_y) in upsampling_taps(y, 2) {
+                    let block_x = block_x.min(blocks_per_row - 1);
+                    let block_y = block_y.min(block_rows - 1);
+                    let block = blocks[(block_y * blocks_per_row + block_x) as usize];
+                    for i in 0..2 {
+                        chroma[i] += weight_x * weight_y * block[i];
+                    }
+                }
+            }
+            let luma = ycbcr[(y * width + x) as usize][0];
+            Rgb(ycbcr_to_rgb([luma, chroma[0], chroma[1]]).map(|v| (v + 0.5) as u8))
+        })
+    }
+
+    #[test]
+    fn subsampled_chroma_is_inverted_per_block() -> anyhow::Result<()> {
+        let alpha = |x: u32, y: u32| match (x, y) {
+            (40..=57, 30..=41) if x % 4 < 2 => 0.6,
+            (40..=57, 30..=41) => 0.8,
+            _ => 1.0,
+        };
+        let color = [230.0, 210.0, 190.0];
+        let black = blend(&RgbImage::from_pixel(64, 48, Rgb([0; 3])), alpha, color);
+        let white = blend(&RgbImage::from_pixel(64, 48, Rgb([255; 3])), alpha, color);
+        let subsampling = ChromaSubsampling {
+            width: 2,
+            height: 2,
+        };
+        // 原图和加上水印的图片都以4:2:0保存
+        let original = gradient(64, 48);
+        let expected = subsample_chroma(&original);
+        let watermarked = subsample_chroma(&blend(&original, alpha, color));
+        // 分别在RGB空间和YCbCr空间中去水印，返回与直接保存原图的结果的最大差距
+        let error = |mode: InversionMode| -> anyhow::Result<u8> {
+            let model = WatermarkModel::new(&black, &white, plain_options(mode))?;
+            let mut page = Page::Rgb(watermarked.clone(), subsampling);
+            remove_in_place(&model, &mut page)?;
+            Ok(max_difference(page.as_raw(), expected.as_raw()))
+        };
+        let (rgb_error, ycbcr_error) = (error(InversionMode::Rgb)?, error(InversionMode::YCbCr)?);
+        // 逐像素反推色度会把色度降采样的误差放大，留下彩边
+        assert!(ycbcr_error <= 6);
+        assert!(rgb_error >= ycbcr_error * 3);
+        Ok(())
+    }
+}
@@ -5,7 +5,7 @@
 
 
 export const commands = {
-async generateBackground(mangaDir: string, rectData: RectData | null, width: number, height: number) : Promise<Result<null, CommandError>> {
+async generateBackground(mangaDir: string, rectData: RectData | null, width: number, height: number) : Promise<Result<string | null, CommandError>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("generate_background", { mangaDir, rectData, width, height }) };
 } catch (e) {
@@ -13,15 +13,42 @@ async generateBackground(mangaDir: string, rectData: RectData | null, width: num
     else return { status: "error", error: e  as any };
 }
 },
-async removeWatermark(mangaDir: string, outputDir: string, format: ImageFormat, optimize: boolean, backgroundsData: ([JpgImageData, JpgImageData])[]) : Promise<Result<null, CommandError>> {
+async detectWatermarkRect(mangaDir: string, width: number, height: number) : Promise<Result<RectData, CommandError>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("remove_watermark", { mangaDir, outputDir, format, optimize, backgroundsData }) };
+    return { status: "ok", data: await TAURI_INVOKE("detect_watermark_rect", { mangaDir, width, height }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
 },
-async openImage(path: string) : Promise<Result<JpgImageData, CommandError>> {
+async getBackgroundCandidates(mangaDir: string, rectData: RectData, width: number, height: number) : Promise<Result<BackgroundCandidate[], CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("get_background_candidates", { mangaDir, rectData, width, height }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async generateBackgroundFromPair(mangaDir: string, rectData: RectData, width: number, height: number, blackPath: string, whitePath: string) : Promise<Result<null, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("generate_background_from_pair", { mangaDir, rectData, width, height, blackPath, whitePath }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async cancelGenerateBackground() : Promise<void> {
+    await TAURI_INVOKE("cancel_generate_background");
+},
+async removeWatermark(mangaDir: string, outputDir: string, backgroundsData: ([ImageData, ImageData])[]) : Promise<Result<RemoveWatermarkReport, CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("remove_watermark", { mangaDir, outputDir, backgroundsData }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async openImage(path: string) : Promise<Result<ImageData, CommandError>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("open_image", { path }) };
 } catch (e) {
@@ -37,8 +64,8 @@ async getMangaDirData(mangaDir: string) : Promise<Result<MangaDirData[], Command
     else return { status: "error", error: e  as any };
 }
 },
-async getJpgImageInfos(mangaDir: string) : Promise<JpgImageInfo[]> {
-    return await TAURI_INVOKE("get_jpg_image_infos", { mangaDir });
+async getImageInfos(mangaDir: string) : Promise<ImageInfo[]> {
+    return await TAURI_INVOKE("get_image_infos", { mangaDir });
 },
 async showPathInFileManager(path: string) : Promise<void> {
     await TAURI_INVOKE("show_path_in_file_manager", { path });
@@ -59,6 +86,14 @@ async getBackgroundDirAbsPath(mangaDir: string, width: number, height: number) :
     else return { status: "error", error: e  as any };
 }
 },
+async getBackgroundVariants(mangaDir: string, width: number, height: number) : Promise<Result<([ImageData, ImageData])[], CommandError>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("get_background_variants", { mangaDir, width, height }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async getConfig() : Promise<Config> {
     return await TAURI_INVOKE("get_config");
 },
@@ -76,11 +111,17 @@ async saveConfig(config: Config) : Promise<Result<null, CommandError>> {
 
 
 export const events = __makeEvents__<{
+generateBackgroundEndEvent: GenerateBackgroundEndEvent,
+generateBackgroundProgressEvent: GenerateBackgroundProgressEvent,
+generateBackgroundStartEvent: GenerateBackgroundStartEvent,
 removeWatermarkEndEvent: RemoveWatermarkEndEvent,
 removeWatermarkErrorEvent: RemoveWatermarkErrorEvent,
 removeWatermarkStartEvent: RemoveWatermarkStartEvent,
 removeWatermarkSuccessEvent: RemoveWatermarkSuccessEvent
 }>({
+generateBackgroundEndEvent: "generate-background-end-event",
+generateBackgroundProgressEvent: "generate-background-progress-event",
+generateBackgroundStartEvent: "generate-background-start-event",
 removeWatermarkEndEvent: "remove-watermark-end-event",
 removeWatermarkErrorEvent: "remove-watermark-error-event",
 removeWatermarkStartEvent: "remove-watermark-start-event",
@@ -93,21 +134,201 @@ removeWatermarkSuccessEvent: "remove-watermark-success-event"
 
 /** user-defined types **/
 
+/**
+ * 可以用作背景水印图的图片
+ */
+export type BackgroundCandidate = { path: string; 
+/**
+ * 截图区域的边上的纯色背景颜色
+ */
+color: [number, number, number]; 
+/**
+ * 截图区域的边上的像素与背景颜色的平均差距，越小说明噪点越少
+ */
+noise: number; 
+/**
+ * 截图区域内不是背景颜色的像素比例，即水印覆盖的比例
+ */
+coverage: number }
+/**
+ * 判断图片是否满足背景条件时使用的阈值，用于容忍JPEG压缩带来的噪点
+ */
+export type BackgroundThresholds = { 
+/**
+ * 像素每个通道与背景颜色的差距都不超过这个值时，认为是背景颜色
+ */
+colorTolerance: number; 
+/**
+ * 截图区域的边上是背景颜色的像素不少于这个比例时，认为边上是纯色
+ */
+minSolidBorderRatio: number; 
+/**
+ * 截图区域内是背景颜色的像素超过这个比例时，认为截图区域内没有水印
+ */
+maxPlainRatio: number }
 export type CommandError = string
-export type Config = { outputDir: string; outputFormat: ImageFormat; outputOptimize: boolean }
+export type Config = { outputDir: string; outputFormat: ImageFormat; outputOptimize: boolean; 
+/**
+ * 以JPEG输出时是否只重新编码与水印相交的块，其他块保留原图的DCT系数
+ */
+preserveJpegBlocks: boolean; 
+/**
+ * 以JPEG输出的图片尺寸超过JPEG的上限时的处理方式
+ */
+oversizedJpegPolicy: OversizedJpegPolicy; 
+/**
+ * 输出图片的元数据的处理方式
+ */
+metadataPolicy: MetadataPolicy; 
+/**
+ * 水印任意一个通道的alpha小于这个值的像素用周围的像素修补，为0(默认)时不修补
+ */
+inpaintAlphaThreshold: number; 
+/**
+ * 反推原图时使用的颜色空间
+ */
+inversionMode: InversionMode; 
+/**
+ * 对齐水印时在水印原本的位置附近搜索的最大平移量，为0时不搜索
+ */
+alignmentSearchRadius: number; 
+/**
+ * 是否在整数平移量的基础上进一步进行亚像素对齐
+ */
+subpixelAlignment: boolean; 
+/**
+ * 是否对去过水印的区域去块和降噪，减轻被放大的JPEG块效应和噪点
+ */
+deblocking: boolean; 
+/**
+ * 是否给没有对应尺寸背景水印图的图片借用其他尺寸的去水印模型，关闭(默认)时这些图片直接复制
+ */
+transplantModels: boolean; 
+/**
+ * 判断图片是否满足背景条件时使用的阈值
+ */
+backgroundThresholds: BackgroundThresholds }
+export type FlaggedImage = { imgPath: string; quality: RemovalQuality }
+export type GenerateBackgroundEndEvent = GenerateBackgroundEndEventPayload
+export type GenerateBackgroundEndEventPayload = { width: number; height: number }
+export type GenerateBackgroundProgressEvent = GenerateBackgroundProgressEventPayload
+export type GenerateBackgroundProgressEventPayload = { width: number; height: number; 
+/**
+ * 已扫描的图片数量
+ */
+current: number; 
+/**
+ * 已找到的满足背景条件的图片数量
+ */
+candidates: number }
+export type GenerateBackgroundStartEvent = GenerateBackgroundStartEventPayload
+export type GenerateBackgroundStartEventPayload = { width: number; height: number; 
+/**
+ * 需要扫描的图片数量
+ */
+total: number }
+export type ImageData = { info: ImageInfo; 
+/**
+ * 图片的MIME类型，比如`image/jpeg`、`image/png`
+ */
+mimeType: string; base64: string }
 export type ImageFormat = "Jpeg" | "Png"
-export type JpgImageData = { info: JpgImageInfo; base64: string }
-export type JpgImageInfo = { width: number; height: number; path: string }
-export type MangaDirData = { width: number; height: number; count: number; blackBackground: JpgImageData | null; whiteBackground: JpgImageData | null }
+export type ImageInfo = { width: number; height: number; path: string }
+/**
+ * 反推原图时使用的颜色空间
+ */
+export type InversionMode = 
+/**
+ * 在RGB空间中逐通道反推
+ */
+"Rgb" | 
+/**
+ * 在YCbCr空间中反推，亮度逐像素反推，色度按照JPEG的色度采样分块反推，可以消除彩色图片上水印周围的彩边
+ */
+"YCbCr"
+export type MangaDirData = { width: number; height: number; count: number; blackBackground: ImageData | null; whiteBackground: ImageData | null; 
+/**
+ * 同一尺寸除第一种以外的其他水印的背景水印图，每种水印对应一对(黑色背景, 白色背景)
+ */
+variantBackgrounds: ([ImageData, ImageData])[] }
+/**
+ * 图片元数据(EXIF、ICC颜色配置文件、注释等)的处理方式
+ */
+export type MetadataPolicy = 
+/**
+ * 重新编码的图片保留原图的EXIF和ICC颜色配置文件，原样复制的图片保留所有元数据
+ */
+"Preserve" | 
+/**
+ * 删除所有图片的元数据，包括原样复制的图片，避免泄露下载工具写入的账号等信息
+ */
+"Strip"
+/**
+ * 以JPEG输出的图片的宽或高超过JPEG的上限(65535)时的处理方式
+ */
+export type OversizedJpegPolicy = 
+/**
+ * 改为以PNG输出
+ */
+"Png" | 
+/**
+ * 按照高度切分为多张编号的JPEG，宽度超过上限时仍以PNG输出
+ */
+"Split"
 export type RectData = { left: number; top: number; right: number; bottom: number }
+/**
+ * 单张图片去水印的质量
+ */
+export type RemovalQuality = { 
+/**
+ * 水印覆盖的像素中，计算结果明显超出`[0, 255]`而被截断的通道所占的比例
+ */
+clippedRatio: number; 
+/**
+ * 去水印后与去水印前水印边缘处平均梯度的比值，越小说明水印残留越少
+ */
+edgeResidual: number }
 export type RemoveWatermarkEndEvent = RemoveWatermarkEndEventPayload
 export type RemoveWatermarkEndEventPayload = { dirPath: string }
 export type RemoveWatermarkErrorEvent = RemoveWatermarkErrorEventPayload
 export type RemoveWatermarkErrorEventPayload = { dirPath: string; imgPath: string; errMsg: string }
+export type RemoveWatermarkReport = { 
+/**
+ * 尺寸有对应的背景水印图，但检测不到水印而被原样复制的图片
+ */
+skippedImgPaths: string[]; 
+/**
+ * 去水印质量不达标，可能去除水印失败的图片
+ */
+flaggedImages: FlaggedImage[]; 
+/**
+ * 第一次去水印质量不达标，经过自动重试后得到更好结果的图片
+ */
+retriedImgPaths: string[]; 
+/**
+ * 没有对应尺寸的背景水印图，借用其他尺寸的背景水印图(按照右下角对齐移植或者缩放后移植)去除水印的图片
+ */
+transplantedImgPaths: string[]; 
+/**
+ * 尺寸超过JPEG的上限，按照`OversizedJpegPolicy`改为以PNG输出或者切分后输出的图片
+ */
+oversizedImgPaths: string[]; 
+/**
+ * 带有透明通道，为了保留透明通道而改为以PNG输出的图片
+ */
+alphaImgPaths: string[]; 
+/**
+ * 读取元数据失败，输出时没有带上元数据的图片
+ */
+metadataFailedImgPaths: string[] }
 export type RemoveWatermarkStartEvent = RemoveWatermarkStartEventPayload
 export type RemoveWatermarkStartEventPayload = { dirPath: string; total: number }
 export type RemoveWatermarkSuccessEvent = RemoveWatermarkSuccessEventPayload
-export type RemoveWatermarkSuccessEventPayload = { dirPath: string; imgPath: string; current: number }
+export type RemoveWatermarkSuccessEventPayload = { dirPath: string; imgPath: string; current: number; 
+/**
+ * 去水印的质量，图片被原样复制时为`None`
+ */
+quality: RemovalQuality | null }
 
 /** tauri-specta globals **/
 
@@ -50,5 +50,11 @@ export async function autoGenerateBackground(
     })
     return false
   }
+  if (result.data !== null) {
+    notification.warning({
+      title: `自动生成背景水印图(${width}x${height})时无法自动定位水印`,
+      description: result.data,
+    })
+  }
   return true
 }